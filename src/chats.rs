@@ -0,0 +1,116 @@
+use std::{
+  collections::BTreeSet,
+  fs,
+  io::ErrorKind,
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Chats enabled or disabled with `/enable` and `/disable`, on top of `enabled-chats`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct ChatOverrides {
+  enabled: BTreeSet<i64>,
+  disabled: BTreeSet<i64>,
+}
+
+impl ChatOverrides {
+  /// Whether `chat_id` was enabled or disabled at runtime, `None` if neither.
+  fn get(&self, chat_id: i64) -> Option<bool> {
+    if self.disabled.contains(&chat_id) {
+      Some(false)
+    } else if self.enabled.contains(&chat_id) {
+      Some(true)
+    } else {
+      None
+    }
+  }
+
+  fn set(&mut self, chat_id: i64, enabled: bool) {
+    let (add, remove) = if enabled {
+      (&mut self.enabled, &mut self.disabled)
+    } else {
+      (&mut self.disabled, &mut self.enabled)
+    };
+    remove.remove(&chat_id);
+    add.insert(chat_id);
+  }
+}
+
+/// [`ChatOverrides`] saved to `chats-file` on every change.
+#[derive(Debug)]
+pub(crate) struct RuntimeChats {
+  path: PathBuf,
+  overrides: Mutex<ChatOverrides>,
+}
+
+impl RuntimeChats {
+  /// Loads the overrides from `path`, a missing file yields none.
+  pub(crate) fn load(path: PathBuf) -> Result<Self> {
+    let overrides = match fs::read_to_string(&path) {
+      Ok(str) => toml::from_str(&str)
+        .with_context(|| format!("Failed to parse chats file: {}", path.to_string_lossy()))?,
+      Err(err) if err.kind() == ErrorKind::NotFound => ChatOverrides::default(),
+      Err(err) => {
+        return Err(err)
+          .with_context(|| format!("Failed to read chats file: {}", path.to_string_lossy()))
+      },
+    };
+    Ok(Self {
+      path,
+      overrides: Mutex::new(overrides),
+    })
+  }
+
+  /// Whether `chat_id` was enabled or disabled at runtime, `None` if neither.
+  pub(crate) fn get(&self, chat_id: i64) -> Option<bool> {
+    self.overrides.lock().unwrap().get(chat_id)
+  }
+
+  /// Enables or disables `chat_id`, kept as it was if saving fails.
+  pub(crate) fn set(&self, chat_id: i64, enabled: bool) -> Result<()> {
+    let mut overrides = self.overrides.lock().unwrap();
+    let mut updated = overrides.clone();
+    updated.set(chat_id, enabled);
+    save(&self.path, &updated)?;
+    *overrides = updated;
+    Ok(())
+  }
+}
+
+/// Writes to a temporary file first, then renames it over `path`.
+fn save(path: &Path, overrides: &ChatOverrides) -> Result<()> {
+  let str = toml::to_string(overrides).context("Failed to serialize chats")?;
+  let tmp = path.with_extension("tmp");
+  fs::write(&tmp, str)
+    .with_context(|| format!("Failed to write chats file: {}", tmp.to_string_lossy()))?;
+  fs::rename(&tmp, path)
+    .with_context(|| format!("Failed to replace chats file: {}", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn enable_and_disable() {
+    let path = std::env::temp_dir().join(format!("fuckburl-bot-chats-{}.toml", std::process::id()));
+    let chats = RuntimeChats::load(path.clone()).unwrap();
+    assert_eq!(None, chats.get(-100123));
+
+    chats.set(-100123, true).unwrap();
+    chats.set(-100456, false).unwrap();
+    assert_eq!(Some(true), chats.get(-100123));
+    chats.set(-100123, false).unwrap();
+    assert_eq!(Some(false), chats.get(-100123));
+
+    let reloaded = RuntimeChats::load(path.clone()).unwrap();
+    assert_eq!(Some(false), reloaded.get(-100123));
+    assert_eq!(Some(false), reloaded.get(-100456));
+
+    fs::remove_file(&path).unwrap();
+  }
+}
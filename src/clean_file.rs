@@ -0,0 +1,53 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+use fuckburl_bot::replacer::{replace_all, ReplaceOutcome};
+
+/// Cleans each line of `path` with the default options, printing `original -> cleaned` or
+/// `unchanged`, and fails if any line failed so CI notices.
+pub(crate) async fn run(path: &Path) -> Result<()> {
+  let text = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+  let mut failed = 0;
+  for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+    match replace_all(line, &HashSet::new()).await {
+      Ok(outcome) => println!("{}", render(line, &outcome)),
+      Err(err) => {
+        failed += 1;
+        println!("{line} -> error: {err:#}");
+      },
+    }
+  }
+  if failed > 0 {
+    bail!("Failed to clean {failed} line(s)");
+  }
+  Ok(())
+}
+
+fn render(original: &str, outcome: &ReplaceOutcome) -> String {
+  if outcome.changed {
+    format!("{original} -> {}", outcome.text)
+  } else {
+    format!("{original} unchanged")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn render_cleaned_and_unchanged() {
+    let dirty = "https://www.pixiv.net/en/artworks/12345678";
+    let outcome = replace_all(dirty, &HashSet::new()).await.unwrap();
+    assert_eq!(
+      "https://www.pixiv.net/en/artworks/12345678 -> https://www.pixiv.net/artworks/12345678",
+      render(dirty, &outcome)
+    );
+
+    let clean = "https://example.com/";
+    let outcome = replace_all(clean, &HashSet::new()).await.unwrap();
+    assert_eq!("https://example.com/ unchanged", render(clean, &outcome));
+  }
+}
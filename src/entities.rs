@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use frankenstein::{MessageEntity, MessageEntityType};
+
+use fuckburl_bot::replacer::{replace_all, replace_all_with_deadline, Platform, ReplaceOutcome};
+use tokio::time::Instant;
+
+use crate::markup::Formatter;
+
+/// Part of a message's text, split at its `text_link` entities.
+#[derive(Debug, PartialEq)]
+enum Segment<'a> {
+  Plain(&'a str),
+  /// Display text hyperlinked to a possibly different url
+  Link {
+    text: &'a str,
+    url: &'a str,
+  },
+}
+
+/// Converts an entity offset, counted in UTF-16 code units, to a byte index of `text`.
+fn byte_index(text: &str, utf16_offset: usize) -> Option<usize> {
+  let mut units = 0;
+  for (index, c) in text.char_indices() {
+    if units == utf16_offset {
+      return Some(index);
+    }
+    units += c.len_utf16();
+  }
+  (units == utf16_offset).then_some(text.len())
+}
+
+fn split_text_links<'a>(text: &'a str, entities: &'a [MessageEntity]) -> Vec<Segment<'a>> {
+  let mut links = entities
+    .iter()
+    .filter(|entity| entity.type_field == MessageEntityType::TextLink)
+    .filter_map(|entity| {
+      let start = byte_index(text, entity.offset as usize)?;
+      let end = byte_index(text, entity.offset as usize + entity.length as usize)?;
+      Some((start, end, entity.url.as_deref()?))
+    })
+    .collect::<Vec<_>>();
+  links.sort_by_key(|(start, ..)| *start);
+
+  let mut segments = Vec::new();
+  let mut pos = 0;
+  for (start, end, url) in links {
+    // Telegram doesn't nest links, skip anything malformed.
+    if start < pos {
+      continue;
+    }
+    if start > pos {
+      segments.push(Segment::Plain(&text[pos..start]));
+    }
+    segments.push(Segment::Link {
+      text: &text[start..end],
+      url,
+    });
+    pos = end;
+  }
+  if pos < text.len() {
+    segments.push(Segment::Plain(&text[pos..]));
+  }
+  segments
+}
+
+/// Adds what cleaning a segment found to the `outcome` of the whole text, except its text.
+pub(crate) fn merge(outcome: &mut ReplaceOutcome, segment: ReplaceOutcome) {
+  outcome.changed |= segment.changed;
+  outcome.replacements.extend(segment.replacements);
+  for key in segment.affiliate_params {
+    if !outcome.affiliate_params.contains(&key) {
+      outcome.affiliate_params.push(key);
+    }
+  }
+}
+
+/// Cleans `text` along with the urls hidden behind its `text_link` entities, leaving short links
+/// still resolving at `deadline` as they are.
+///
+/// Returns the cleaned text formatted with `formatter`, keeping those links with cleaned urls.
+pub(crate) async fn replace_with_entities(
+  text: &str,
+  entities: Option<&[MessageEntity]>,
+  disabled: &HashSet<Platform>,
+  formatter: &dyn Formatter,
+  deadline: Option<Instant>,
+) -> Result<(String, ReplaceOutcome)> {
+  let clean = |text| async move {
+    match deadline {
+      Some(deadline) => replace_all_with_deadline(text, disabled, deadline).await,
+      None => replace_all(text, disabled).await,
+    }
+  };
+  let mut html = String::with_capacity(text.len());
+  let mut outcome = ReplaceOutcome {
+    text: String::with_capacity(text.len()),
+    changed: false,
+    replacements: Vec::new(),
+    affiliate_params: Vec::new(),
+  };
+  for segment in split_text_links(text, entities.unwrap_or_default()) {
+    match segment {
+      Segment::Plain(plain) => {
+        let cleaned = clean(plain).await?;
+        html.push_str(&formatter.escape(&cleaned.text));
+        outcome.text.push_str(&cleaned.text);
+        merge(&mut outcome, cleaned);
+      },
+      Segment::Link { text, url } => {
+        let cleaned = clean(url).await?;
+        html.push_str(&formatter.link(text, &cleaned.text));
+        outcome.text.push_str(text);
+        merge(&mut outcome, cleaned);
+      },
+    }
+  }
+  Ok((html, outcome))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::markup::Html;
+
+  fn text_link(offset: u16, length: u16, url: &str) -> MessageEntity {
+    MessageEntity::builder()
+      .type_field(MessageEntityType::TextLink)
+      .offset(offset)
+      .length(length)
+      .url(url.to_string())
+      .build()
+  }
+
+  #[test]
+  fn split_at_text_links() {
+    // "链接" is two UTF-16 units but six bytes
+    let text = "看 链接 and more";
+    let entities = [text_link(2, 2, "https://example.com/")];
+    assert_eq!(
+      vec![
+        Segment::Plain("看 "),
+        Segment::Link {
+          text: "链接",
+          url: "https://example.com/"
+        },
+        Segment::Plain(" and more"),
+      ],
+      split_text_links(text, &entities)
+    );
+    assert_eq!(vec![Segment::Plain(text)], split_text_links(text, &[]));
+  }
+
+  #[tokio::test]
+  async fn clean_text_link_href() {
+    let text = "buy this now";
+    let entities = [text_link(
+      4,
+      4,
+      "https://item.m.jd.com/product/100026923531.html?utm_source=iosapp&utm_medium=appshare",
+    )];
+    let (html, outcome) =
+      replace_with_entities(text, Some(&entities), &HashSet::new(), &Html, None)
+        .await
+        .unwrap();
+    assert!(outcome.changed);
+    assert_eq!(text, outcome.text);
+    assert_eq!(
+      "buy <a href=\"https:&#x2f;&#x2f;item.m.jd.com&#x2f;product&#x2f;100026923531.html\">this</a> now",
+      html
+    );
+  }
+
+  #[tokio::test]
+  async fn escape_href_attribute() {
+    let text = "<this>";
+    let entities = [text_link(0, 6, "https://example.com/a?x=1&y=\"2\"")];
+    let (html, _) = replace_with_entities(text, Some(&entities), &HashSet::new(), &Html, None)
+      .await
+      .unwrap();
+    assert_eq!(
+      "<a href=\"https:&#x2f;&#x2f;example.com&#x2f;a?x=1&amp;y=&quot;2&quot;\">&lt;this&gt;</a>",
+      html
+    );
+  }
+}
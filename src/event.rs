@@ -1,105 +1,980 @@
-use std::{fmt::Display, sync::Arc};
+use std::{collections::HashSet, fmt::Display, future::Future, sync::Arc, time::Duration};
 
-use anyhow::{Context, Ok, Result};
+use anyhow::{Context, Result};
+use chrono::Local;
 use frankenstein::{
-  AsyncApi, AsyncTelegramApi, DeleteMessageParams, MessageOrigin, ParseMode, ReplyParameters,
+  AsyncApi, AsyncTelegramApi, Chat, ChatMember, CopyMessageParams, DeleteMessageParams,
+  EditMessageCaptionParams, EditMessageTextParams, FileUpload, GetChatAdministratorsParams,
+  InputMediaAudio, InputMediaDocument, InputMediaPhoto, InputMediaVideo, LinkPreviewOptions, Media,
+  Message, MessageEntity, MessageOrigin, ParseMode, ReplyParameters, SendMediaGroupParams,
   SendMessageParams, Update, UpdateContent, User,
 };
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use tokio::{sync::Semaphore, task::JoinSet};
 
-use crate::{replacer::replace_all, start_time, Config};
+use fuckburl_bot::replacer::{replace_all, Platform, ReplaceOutcome};
+
+use crate::{
+  entities::{merge, replace_with_entities},
+  markup::Formatter,
+  media_group::MEDIA_GROUP_WINDOW,
+  message::{render, CAPTION_LIMIT, TEXT_LIMIT},
+  now_secs, resumed,
+  retry::with_retry,
+  start_time,
+  tg_error::{
+    api_description, is_chat_gone, is_dm_unavailable, is_missing_delete_permission,
+    is_too_old_to_delete,
+  },
+  Config, EnabledChat, MessageTemplate, RepostMode, Shared,
+};
 use std::fmt::Write;
 
-fn write_user(text: &mut String, user: &User) {
+fn write_user(text: &mut String, user: &User, formatter: &dyn Formatter) {
   match user.username {
     Some(ref at) => {
-      write!(text, "@{at}").unwrap();
+      text.push_str(&formatter.escape(&format!("@{at}")));
     },
     None => {
-      write!(text, r#"<a href="tg://user?id={}">"#, user.id).unwrap();
-      text.push_str(&v_htmlescape::escape(&user.first_name).to_string());
-      if let Some(ref last) = user.last_name {
-        write!(text, " {}", v_htmlescape::escape(last)).unwrap();
-      }
-      text.push_str("</a>");
+      let name = match user.last_name {
+        Some(ref last) => format!("{} {last}", user.first_name),
+        None => user.first_name.clone(),
+      };
+      text.push_str(&formatter.mention(&name, user.id));
     },
   }
 }
 
+pub(crate) fn spawn_process_update(
+  tasks: &mut JoinSet<()>,
+  api: Arc<AsyncApi>,
+  config: Arc<Config>,
+  shared: Arc<Shared>,
+  update: Update,
+) {
+  tasks.spawn(async move {
+    let result = process_update(&api, config, &shared, update)
+      .await
+      .with_context(|| "Failed to process update".to_string());
+    if let Err(err) = result {
+      log::error!("{err:?}");
+    }
+  });
+}
+
+/// When cleaning an update must be done resolving short links, with `[webhook]`
+/// `resolve-deadline-ms`.
+fn resolve_deadline(config: &Config) -> Option<tokio::time::Instant> {
+  let webhook = config.webhook.as_ref()?;
+  Some(tokio::time::Instant::now() + Duration::from_millis(webhook.resolve_deadline_ms))
+}
+
+/// Runs `task` once one of `permits` is free, so a burst of updates is processed a few at a time.
+///
+/// Only cleaning and sending hold a permit, not waits like the media group window, the delete
+/// delay or the rate limiter, which would otherwise hold up unrelated updates.
+async fn with_permit<T>(permits: &Semaphore, task: impl Future<Output = Result<T>>) -> Result<T> {
+  let _permit = permits
+    .acquire()
+    .await
+    .context("Update permits are closed")?;
+  task.await
+}
+
 pub(crate) async fn process_update(
   api: &AsyncApi,
   config: Arc<Config>,
+  shared: &Shared,
   update: Update,
 ) -> Result<()> {
-  let UpdateContent::Message(msg) = update.content else {
-    info!("Unsupported message type: {}", MessageType(update.content));
+  shared.metrics.updates.increment(1);
+  let (msg, edited) = match update.content {
+    UpdateContent::Message(msg) => (msg, false),
+    UpdateContent::EditedMessage(msg) if config.handle_edits => (msg, true),
+    UpdateContent::ChannelPost(msg) if config.enable_channels => (msg, false),
+    UpdateContent::EditedChannelPost(msg) if config.enable_channels && config.handle_edits => {
+      (msg, true)
+    },
+    content => {
+      info!("Unsupported message type: {}", MessageType(content));
+      return Ok(());
+    },
+  };
+
+  if !resumed() && msg.edit_date.unwrap_or(msg.date) < start_time() {
     return Ok(());
+  }
+
+  if let Some((command, args)) = msg
+    .text
+    .as_deref()
+    .filter(|_| !edited)
+    .and_then(|text| parse_command(text, &shared.username))
+  {
+    match command {
+      "preview" => return preview(api, shared, &msg, args).await,
+      "stats" => return stats(api, &config, shared, &msg).await,
+      "leaderboard" => return leaderboard(api, &config, shared, &msg).await,
+      "reload" => return reload(api, &config, shared, &msg).await,
+      "enable" | "disable" => {
+        return set_enabled(api, &config, shared, &msg, command == "enable").await
+      },
+      // Other commands may still carry links to clean.
+      _ => {},
+    }
+  }
+  let chat_id = msg.chat.id.to_string();
+  let configured = config.enabled_chats.iter().find(|chat| {
+    chat.id == chat_id
+      || msg
+        .chat
+        .username
+        .as_ref()
+        .is_some_and(|usr| &chat.id == usr)
+  });
+  let enabled_at_runtime;
+  let chat = match (shared.chats.get(msg.chat.id), configured) {
+    (Some(false), _) | (None, None) => return Ok(()),
+    (_, Some(chat)) => chat,
+    (Some(true), None) => {
+      enabled_at_runtime = EnabledChat::new(chat_id);
+      &enabled_at_runtime
+    },
   };
 
-  if msg.date < start_time() {
+  debug!("Message id: {}/{}", msg.chat.id, msg.message_id);
+
+  let quiet_hours = chat.quiet_hours.as_ref().or(config.quiet_hours.as_ref());
+  if quiet_hours.is_some_and(|quiet| quiet.contains(Local::now().time())) {
+    debug!("In quiet hours, skipping");
     return Ok(());
   }
-  let contains_id = config.enabled_chats.contains(&msg.chat.id.to_string());
-  let contains_username = msg
-    .chat
-    .username
-    .is_some_and(|usr| config.enabled_chats.contains(&usr));
-  if !contains_id && !contains_username {
+
+  if msg
+    .from
+    .as_deref()
+    .is_some_and(|user| is_exempt(&config.exempt_users, user))
+  {
+    debug!("Sender is exempt, skipping");
+    return Ok(());
+  }
+
+  // Cleaning another bot's reposts could bounce between the two forever.
+  if !config.process_bot_messages && is_other_bot(&msg, shared.bot_id) {
+    debug!("Sender is a bot, skipping");
+    return Ok(());
+  }
+
+  if let Some(group_id) = msg.media_group_id.clone() {
+    // The album is reposted as a whole, which an edit of one item can't do.
+    if edited || !shared.media_groups.push(&group_id, msg) {
+      return Ok(());
+    }
+    tokio::time::sleep(MEDIA_GROUP_WINDOW).await;
+    let group = shared.media_groups.take(&group_id);
+    return process_media_group(api, &config, shared, &chat.disable, group).await;
+  }
+
+  let Some((text, entities)) = message_text(&msg) else {
     return Ok(());
   };
 
-  debug!("Message id: {}/{}", msg.chat.id, msg.message_id);
+  if is_ignored(&config.ignore_marker, text) {
+    return Ok(());
+  }
 
-  let text = if let Some(text) = msg.text.clone() {
-    text
+  let formatter = config.parse_mode.formatter();
+  let deadline = resolve_deadline(&config);
+  let (html, outcome) = with_permit(&shared.update_permits(), async {
+    let (mut html, mut outcome) =
+      replace_with_entities(text, entities, &chat.disable, formatter, deadline)
+        .await
+        .context("Failed to replace text")?;
+    // The repost may outlive the quoted message, so a cleaned quote is reposted too.
+    if let Some((quote, entities)) = quote_text(&msg) {
+      let (quote_html, quote_outcome) =
+        replace_with_entities(quote, entities, &chat.disable, formatter, deadline)
+          .await
+          .context("Failed to replace quote")?;
+      if quote_outcome.changed {
+        html = format!("{}\n{html}", formatter.blockquote(&quote_html));
+        merge(&mut outcome, quote_outcome);
+      }
+    }
+    Ok((html, outcome))
+  })
+  .await?;
+  shared.stats.record(&outcome);
+  shared.metrics.record(&outcome);
+  if !outcome.changed {
+    return Ok(());
+  }
+
+  info!("Replacing message {}/{}", msg.chat.id, msg.message_id);
+
+  if is_own(&msg, shared.bot_id) {
+    return edit_own(api, &config, shared, &msg, &html).await;
+  }
+
+  if is_minor_change(text, &outcome, config.min_change_ratio) {
+    debug!("Cleaned links are a small part of the message, replying with them instead");
+    let urls = cleaned_urls(&outcome, formatter);
+    let reply = ReplyParameters::builder()
+      .message_id(msg.message_id)
+      .build();
+    let preview_url = primary_url(&outcome);
+    return send_formatted(api, &config, shared, &msg, &urls, Some(reply), preview_url).await;
+  }
+
+  let Some(forwarded) = forwarded_footer(&config, msg.forward_origin.as_deref()) else {
+    return Ok(());
+  };
+
+  let mut text = render_repost(
+    &config.message,
+    &author(msg.from.as_deref(), msg.sender_chat.as_deref(), formatter),
+    &repost_body(config.repost_mode, html, &outcome, formatter),
+    &outcome,
+    &forwarded,
+    config.flag_affiliate,
+    formatter,
+  );
+  append_footer(
+    &mut text,
+    &config.message_footer,
+    msg.text.is_none(),
+    formatter,
+  );
+
+  // Deleting an edited message is confusing, so unless configured otherwise the cleaned
+  // copy replies to the original instead, as it does when deletion is disabled.
+  let delete_original = config.delete_original && (!edited || config.delete_edited);
+  let reply = reply_parameters(&msg, delete_original);
+  let sent = if msg.text.is_some() {
+    let preview_url = primary_url(&outcome);
+    send_formatted(api, &config, shared, &msg, &text, reply, preview_url).await
   } else {
+    send_captioned(api, &config, shared, &msg, &text, reply).await
+  };
+  if let Err(err) = sent {
+    prune_dead_chat(&config, shared, msg.chat.id, &err);
+    return Err(err);
+  }
+
+  if let Some(from) = msg.from.as_deref() {
+    shared.leaderboard.record(from);
+  }
+  if config.notify_original_sender {
+    notify_sender(api, &config, shared, &msg).await;
+  }
+  if delete_original {
+    delete_delay(&config).await;
+    delete_message(api, &config, shared, &msg).await;
+  }
+
+  Ok(())
+}
+
+/// Reposts an album whose caption has links as a single media group.
+async fn process_media_group(
+  api: &AsyncApi,
+  config: &Config,
+  shared: &Shared,
+  disabled: &HashSet<Platform>,
+  group: Vec<Message>,
+) -> Result<()> {
+  // Telegram usually keeps an album's caption on its first item only.
+  let Some(captioned) = group.iter().find(|msg| msg.caption.is_some()) else {
     return Ok(());
   };
+  let caption = captioned.caption.as_deref().unwrap_or_default();
+  if is_ignored(&config.ignore_marker, caption) {
+    return Ok(());
+  }
 
-  if text.contains("@ignoreme") {
+  let formatter = config.parse_mode.formatter();
+  let (html, outcome) = with_permit(
+    &shared.update_permits(),
+    replace_with_entities(
+      caption,
+      captioned.caption_entities.as_deref(),
+      disabled,
+      formatter,
+      resolve_deadline(config),
+    ),
+  )
+  .await
+  .context("Failed to replace caption")?;
+  shared.stats.record(&outcome);
+  shared.metrics.record(&outcome);
+  if !outcome.changed {
     return Ok(());
   }
 
-  let replaced = replace_all(&text).await.context("Failed to replace text")?;
-  if replaced == text {
+  let Some(forwarded) = forwarded_footer(config, captioned.forward_origin.as_deref()) else {
+    return Ok(());
+  };
+  let mut caption = render_repost(
+    &config.message,
+    &author(
+      captioned.from.as_deref(),
+      captioned.sender_chat.as_deref(),
+      formatter,
+    ),
+    &repost_body(config.repost_mode, html, &outcome, formatter),
+    &outcome,
+    &forwarded,
+    config.flag_affiliate,
+    formatter,
+  );
+  append_footer(&mut caption, &config.message_footer, true, formatter);
+  if shared.dry_run {
+    info!(
+      "Dry run, would repost album {}/{} of {} items with caption:\n{caption}",
+      captioned.chat.id,
+      captioned.message_id,
+      group.len()
+    );
     return Ok(());
   }
+  // A caption too long for the album follows it as a text message instead.
+  let (mut caption, follow_up) = if formatter.visible_len(&caption) > CAPTION_LIMIT {
+    (None, Some(caption))
+  } else {
+    (Some(caption), None)
+  };
 
-  info!("Replacing message {}/{}", msg.chat.id, msg.message_id);
+  let media = group
+    .iter()
+    .filter_map(|msg| input_media(msg, caption.take(), formatter.parse_mode()))
+    .collect::<Vec<_>>();
+  // Reposting part of an album would lose the rest once the originals are deleted.
+  if media.len() != group.len() {
+    warn!(
+      "Album {}/{} has unsupported media, skipping",
+      captioned.chat.id, captioned.message_id
+    );
+    return Ok(());
+  }
 
-  let mut text = String::with_capacity(128);
-  write!(text, "Send by ").unwrap();
-  match msg.from {
-    Some(user) => write_user(&mut text, &user),
-    None => {
-      write!(text, "Unknown").unwrap();
+  info!(
+    "Replacing album {}/{} of {} items",
+    captioned.chat.id,
+    captioned.message_id,
+    group.len()
+  );
+
+  let first = &group[0];
+  let mut send_group = SendMediaGroupParams::builder()
+    .chat_id(first.chat.id)
+    .maybe_message_thread_id(topic_thread_id(first))
+    .media(media)
+    .build();
+  send_group.reply_parameters = reply_parameters(first, config.delete_original);
+
+  shared.limiter.acquire(first.chat.id).await;
+  let resp = with_permit(&shared.update_permits(), async {
+    with_retry(&config.retry, || api.send_media_group(&send_group))
+      .await
+      .inspect_err(|_| shared.metrics.send_failures.increment(1))
+      .context("Failed to send media group...")
+  })
+  .await
+  .inspect_err(|err| prune_dead_chat(config, shared, first.chat.id, err))?;
+  debug!("{resp:?}");
+
+  if let Some(text) = follow_up {
+    let reply = resp.result.first().map(|sent| {
+      ReplyParameters::builder()
+        .message_id(sent.message_id)
+        .build()
+    });
+    send_formatted(api, config, shared, first, &text, reply, None).await?;
+  }
+
+  if let Some(from) = captioned.from.as_deref() {
+    shared.leaderboard.record(from);
+  }
+  if config.delete_original {
+    delete_delay(config).await;
+    for msg in &group {
+      delete_message(api, config, shared, msg).await;
+    }
+  }
+
+  Ok(())
+}
+
+/// Sends `text`, formatted in the configured `parse-mode`, to where `original` was, split into
+/// several messages when longer than telegram allows.
+///
+/// The first message previews `preview_url` when set, instead of telegram's pick.
+async fn send_formatted(
+  api: &AsyncApi,
+  config: &Config,
+  shared: &Shared,
+  original: &Message,
+  text: &str,
+  mut reply: Option<ReplyParameters>,
+  mut preview_url: Option<&str>,
+) -> Result<()> {
+  let chat_id = original.chat.id;
+  if shared.dry_run {
+    info!("Dry run, would send to {chat_id}:\n{text}");
+    return Ok(());
+  }
+  for part in config.parse_mode.formatter().split(text, TEXT_LIMIT) {
+    let mut send_msg = send_params(config, original, part, preview_url.take());
+    send_msg.reply_parameters = reply.take();
+
+    shared.limiter.acquire(chat_id).await;
+    let resp = with_permit(&shared.update_permits(), async {
+      with_retry(&config.retry, || api.send_message(&send_msg))
+        .await
+        .inspect_err(|_| shared.metrics.send_failures.increment(1))
+        .context("Failed to send message...")
+    })
+    .await?;
+    debug!("{resp:?}");
+  }
+  Ok(())
+}
+
+/// Whether the cleaned links take up less than `min_ratio` of `text`, by characters, in which case
+/// reposting all of it would be heavy-handed.
+fn is_minor_change(text: &str, outcome: &ReplaceOutcome, min_ratio: f64) -> bool {
+  let len = text.chars().count();
+  if min_ratio <= 0.0 || len == 0 {
+    return false;
+  }
+  let cleaned = outcome
+    .replacements
+    .iter()
+    .map(|replacement| replacement.original.chars().count())
+    .sum::<usize>();
+  (cleaned as f64 / len as f64) < min_ratio
+}
+
+/// The cleaned link a repost should preview, when it's the only one.
+fn primary_url(outcome: &ReplaceOutcome) -> Option<&str> {
+  match outcome.replacements.as_slice() {
+    [only] => Some(&only.replaced),
+    _ => None,
+  }
+}
+
+/// Whether the bot posted `msg` itself, which is then edited instead of deleted and reposted.
+fn is_own(msg: &Message, bot_id: u64) -> bool {
+  msg.from.as_deref().is_some_and(|from| from.id == bot_id)
+}
+
+/// Sends `part` of a repost to where `original` was, previewing `preview_url` if any, or nothing
+/// when `disable-web-page-preview` is set.
+fn send_params(
+  config: &Config,
+  original: &Message,
+  part: String,
+  preview_url: Option<&str>,
+) -> SendMessageParams {
+  let link_preview_options = if config.disable_web_page_preview {
+    Some(LinkPreviewOptions::builder().is_disabled(true).build())
+  } else {
+    preview_url.map(|url| LinkPreviewOptions::builder().url(url.to_string()).build())
+  };
+  SendMessageParams::builder()
+    .chat_id(original.chat.id)
+    .maybe_message_thread_id(topic_thread_id(original))
+    .text(part)
+    .parse_mode(config.parse_mode.formatter().parse_mode())
+    .maybe_link_preview_options(link_preview_options)
+    .build()
+}
+
+/// Disables `chat_id` as if by `/disable` when `err` says the bot can no longer post there and
+/// `auto-prune-dead-chats` is set, so its updates stop failing over and over.
+fn prune_dead_chat(config: &Config, shared: &Shared, chat_id: i64, err: &anyhow::Error) {
+  let gone = err
+    .downcast_ref::<frankenstein::Error>()
+    .and_then(api_description)
+    .is_some_and(is_chat_gone);
+  if !gone || !config.auto_prune_dead_chats {
+    return;
+  }
+  match shared.chats.set(chat_id, false) {
+    Ok(()) => warn!("Chat {chat_id} is gone or the bot was removed, disabling it"),
+    Err(err) => error!("Failed to disable gone chat {chat_id}: {err:?}"),
+  }
+}
+
+/// Whether `msg` was sent by a bot other than this one.
+fn is_other_bot(msg: &Message, bot_id: u64) -> bool {
+  msg
+    .from
+    .as_deref()
+    .is_some_and(|from| from.is_bot && from.id != bot_id)
+}
+
+/// Replaces the text or caption of the bot's own `msg` with the formatted `html`, keeping its
+/// reactions and replies.
+async fn edit_own(
+  api: &AsyncApi,
+  config: &Config,
+  shared: &Shared,
+  msg: &Message,
+  html: &str,
+) -> Result<()> {
+  if shared.dry_run {
+    info!(
+      "Dry run, would edit {}/{} to:\n{html}",
+      msg.chat.id, msg.message_id
+    );
+    return Ok(());
+  }
+  shared.limiter.acquire(msg.chat.id).await;
+  let _permit = shared
+    .update_permits()
+    .acquire_owned()
+    .await
+    .context("Update permits are closed")?;
+  if msg.text.is_some() {
+    let edit_msg = EditMessageTextParams::builder()
+      .chat_id(msg.chat.id)
+      .message_id(msg.message_id)
+      .text(html)
+      .parse_mode(config.parse_mode.formatter().parse_mode())
+      .build();
+    let resp = with_retry(&config.retry, || api.edit_message_text(&edit_msg))
+      .await
+      .inspect_err(|_| shared.metrics.send_failures.increment(1))
+      .context("Failed to edit message...")?;
+    debug!("{resp:?}");
+  } else {
+    let edit_msg = EditMessageCaptionParams::builder()
+      .chat_id(msg.chat.id)
+      .message_id(msg.message_id)
+      .caption(html)
+      .parse_mode(config.parse_mode.formatter().parse_mode())
+      .build();
+    let resp = with_retry(&config.retry, || api.edit_message_caption(&edit_msg))
+      .await
+      .inspect_err(|_| shared.metrics.send_failures.increment(1))
+      .context("Failed to edit caption...")?;
+    debug!("{resp:?}");
+  }
+  Ok(())
+}
+
+/// Reposts the media of `msg` with the formatted `caption`, which follows as a text message when too
+/// long for a caption.
+async fn send_captioned(
+  api: &AsyncApi,
+  config: &Config,
+  shared: &Shared,
+  msg: &Message,
+  caption: &str,
+  reply: Option<ReplyParameters>,
+) -> Result<()> {
+  if shared.dry_run {
+    info!(
+      "Dry run, would repost {}/{} with caption:\n{caption}",
+      msg.chat.id, msg.message_id
+    );
+    return Ok(());
+  }
+  let formatter = config.parse_mode.formatter();
+  let (caption, follow_up) = if formatter.visible_len(caption) > CAPTION_LIMIT {
+    // An empty caption drops the original one instead of keeping it.
+    ("", Some(caption))
+  } else {
+    (caption, None)
+  };
+  let mut copy_msg = CopyMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .maybe_message_thread_id(topic_thread_id(msg))
+    .from_chat_id(msg.chat.id)
+    .message_id(msg.message_id)
+    .caption(caption.to_string())
+    .parse_mode(formatter.parse_mode())
+    .build();
+  copy_msg.reply_parameters = reply;
+
+  shared.limiter.acquire(msg.chat.id).await;
+  let resp = with_permit(&shared.update_permits(), async {
+    with_retry(&config.retry, || api.copy_message(&copy_msg))
+      .await
+      .inspect_err(|_| shared.metrics.send_failures.increment(1))
+      .context("Failed to copy message...")
+  })
+  .await?;
+  debug!("{resp:?}");
+
+  if let Some(text) = follow_up {
+    let reply = ReplyParameters::builder()
+      .message_id(resp.result.message_id)
+      .build();
+    send_formatted(api, config, shared, msg, text, Some(reply), None).await?;
+  }
+  Ok(())
+}
+
+/// The text of `msg` with its entities, or the caption of its media.
+fn message_text(msg: &Message) -> Option<(&str, Option<&[MessageEntity]>)> {
+  match (&msg.text, &msg.caption) {
+    (Some(text), _) => Some((text, msg.entities.as_deref())),
+    (None, Some(caption)) => Some((caption, msg.caption_entities.as_deref())),
+    (None, None) => None,
+  }
+}
+
+/// The part of the replied message quoted by `msg`.
+///
+/// A quote may start or end inside a link, which is then widened to the whole link as found in
+/// the replied message, dropping the quote's entities that no longer line up.
+fn quote_text(msg: &Message) -> Option<(&str, Option<&[MessageEntity]>)> {
+  let quote = msg.quote.as_deref()?;
+  let whole = (&*quote.text, quote.entities.as_deref());
+  let Some((replied, _)) = msg.reply_to_message.as_deref().and_then(message_text) else {
+    return Some(whole);
+  };
+  let Some(start) = replied.find(&quote.text) else {
+    return Some(whole);
+  };
+  let end = start + quote.text.len();
+  let not_space = |c: char| !c.is_whitespace();
+  let start = replied[..start].trim_end_matches(not_space).len();
+  let end = replied.len() - replied[end..].trim_start_matches(not_space).len();
+  if end - start == quote.text.len() {
+    Some(whole)
+  } else {
+    Some((&replied[start..end], None))
+  }
+}
+
+/// The album item of `msg`, with `caption` formatted in `parse_mode`.
+fn input_media(msg: &Message, caption: Option<String>, parse_mode: ParseMode) -> Option<Media> {
+  let parse_mode = caption.as_ref().map(|_| parse_mode);
+  if let Some(photo) = msg.photo.as_ref().and_then(|sizes| sizes.last()) {
+    return Some(Media::Photo(
+      InputMediaPhoto::builder()
+        .media(FileUpload::String(photo.file_id.clone()))
+        .maybe_caption(caption)
+        .maybe_parse_mode(parse_mode)
+        .build(),
+    ));
+  }
+  if let Some(video) = &msg.video {
+    return Some(Media::Video(
+      InputMediaVideo::builder()
+        .media(FileUpload::String(video.file_id.clone()))
+        .maybe_caption(caption)
+        .maybe_parse_mode(parse_mode)
+        .build(),
+    ));
+  }
+  if let Some(document) = &msg.document {
+    return Some(Media::Document(
+      InputMediaDocument::builder()
+        .media(FileUpload::String(document.file_id.clone()))
+        .maybe_caption(caption)
+        .maybe_parse_mode(parse_mode)
+        .build(),
+    ));
+  }
+  if let Some(audio) = &msg.audio {
+    return Some(Media::Audio(
+      InputMediaAudio::builder()
+        .media(FileUpload::String(audio.file_id.clone()))
+        .maybe_caption(caption)
+        .maybe_parse_mode(parse_mode)
+        .build(),
+    ));
+  }
+  None
+}
+
+/// The "forwarded from" footer, `None` for posts forwarded from chats with
+/// `skip-forwarded-channels`, which are left alone.
+fn forwarded_footer(config: &Config, origin: Option<&MessageOrigin>) -> Option<String> {
+  render_forwarded(
+    &config.message,
+    origin,
+    config.skip_forwarded_channels,
+    config.parse_mode.formatter(),
+  )
+}
+
+fn render_forwarded(
+  template: &MessageTemplate,
+  origin: Option<&MessageOrigin>,
+  skip_chats: bool,
+  formatter: &dyn Formatter,
+) -> Option<String> {
+  let user = match origin {
+    None => return Some(String::new()),
+    Some(MessageOrigin::User(origin)) => {
+      let mut user = String::new();
+      write_user(&mut user, &origin.sender_user, formatter);
+      user
     },
+    Some(MessageOrigin::HiddenUser(origin)) => formatter.escape(&origin.sender_user_name),
+    Some(MessageOrigin::Chat(_) | MessageOrigin::Channel(_)) if skip_chats => return None,
+    Some(MessageOrigin::Chat(origin)) => chat_title(&origin.sender_chat, formatter),
+    Some(MessageOrigin::Channel(origin)) => chat_title(&origin.chat, formatter),
+  };
+  Some(render(&template.forwarded, &[("user", &user)]))
+}
+
+/// The escaped title of a group or channel, its username if untitled.
+fn chat_title(chat: &Chat, formatter: &dyn Formatter) -> String {
+  let title = chat
+    .title
+    .as_deref()
+    .or(chat.username.as_deref())
+    .unwrap_or("Unknown");
+  formatter.escape(title)
+}
+
+/// The forum topic of `msg`, reposts go there too instead of the general topic.
+fn topic_thread_id(msg: &Message) -> Option<i32> {
+  // Replies in other chats have a thread id too, which only means something in forums.
+  msg
+    .is_topic_message
+    .unwrap_or(false)
+    .then_some(msg.message_thread_id)
+    .flatten()
+}
+
+/// Replies to what `msg` replied to when it is deleted, otherwise to `msg` itself.
+fn reply_parameters(msg: &Message, delete_original: bool) -> Option<ReplyParameters> {
+  if delete_original {
+    msg
+      .reply_to_message
+      .as_ref()
+      .map(|i| ReplyParameters::builder().message_id(i.message_id).build())
+  } else {
+    Some(
+      ReplyParameters::builder()
+        .message_id(msg.message_id)
+        .build(),
+    )
   }
+}
 
-  writeln!(text, ":\n").unwrap();
+/// Waits the configured `delete-delay-ms` before deleting an original.
+async fn delete_delay(config: &Config) {
+  if config.delete_delay_ms > 0 {
+    tokio::time::sleep(Duration::from_millis(config.delete_delay_ms)).await;
+  }
+}
 
-  text.push_str(&v_htmlescape::escape(&replaced).to_string());
+/// Deletes the original message, the cleaned copy is already sent so failures are only logged.
+async fn delete_message(api: &AsyncApi, config: &Config, shared: &Shared, msg: &Message) {
+  if shared.dry_run {
+    info!("Dry run, would delete {}/{}", msg.chat.id, msg.message_id);
+    return;
+  }
+  let delete_msg = DeleteMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .message_id(msg.message_id)
+    .build();
+  match with_retry(&config.retry, || api.delete_message(&delete_msg)).await {
+    Ok(resp) => debug!("{resp:?}"),
+    Err(err) if api_description(&err).is_some_and(is_too_old_to_delete) => debug!(
+      "Message {}/{} is too old to delete, keeping it",
+      msg.chat.id, msg.message_id
+    ),
+    Err(err) if api_description(&err).is_some_and(is_missing_delete_permission) => {
+      shared.metrics.delete_failures.increment(1);
+      if shared.delete_warned.lock().unwrap().insert(msg.chat.id) {
+        warn!(
+          "Missing permission to delete messages in chat {}, consider `delete-original = false`",
+          msg.chat.id
+        );
+      }
+    },
+    Err(err) => {
+      shared.metrics.delete_failures.increment(1);
+      error!(
+        "Failed to delete message {}/{}: {err:?}",
+        msg.chat.id, msg.message_id
+      );
+    },
+  }
+}
 
-  if let Some(reply_origin) = msg.forward_origin {
-    use MessageOrigin as MO;
-    match *reply_origin {
-      MO::User(user) => {
-        text.push_str("\n\n<i>forwarded from ");
-        write_user(&mut text, &user.sender_user);
-        text.push_str("</i>");
-      },
-      MO::HiddenUser(user) => {
-        text.push_str("\n\n<i>forwarded from ");
-        text.push_str(&v_htmlescape::escape(&user.sender_user_name).to_string());
-        text.push_str("</i>");
-      },
-      MO::Chat(_chat) => {
-        return Ok(());
-      },
-      MO::Channel(_channel) => {
-        return Ok(());
-      },
-    }
+/// Tells the sender of `msg` that its links were cleaned, by DM or, when the bot can't message
+/// them, by a reply to `msg` sent before it's deleted.
+async fn notify_sender(api: &AsyncApi, config: &Config, shared: &Shared, msg: &Message) {
+  let Some(from) = msg.from.as_deref() else {
+    return;
+  };
+  let notice = &config.message.notice;
+  if shared.dry_run {
+    info!("Dry run, would notify {} with:\n{notice}", from.id);
+    return;
+  }
+  let dm = SendMessageParams::builder()
+    .chat_id(from.id as i64)
+    .text(notice)
+    .parse_mode(config.parse_mode.formatter().parse_mode())
+    .build();
+  let err = match api.send_message(&dm).await {
+    Ok(resp) => {
+      debug!("{resp:?}");
+      return;
+    },
+    Err(err) => err,
+  };
+  if !api_description(&err).is_some_and(is_dm_unavailable) {
+    error!("Failed to notify {}: {err:?}", from.id);
+    return;
+  }
+  debug!("Can't message {} directly, replying instead", from.id);
+  let reply = ReplyParameters::builder()
+    .message_id(msg.message_id)
+    .build();
+  if let Err(err) = send_formatted(api, config, shared, msg, notice, Some(reply), None).await {
+    error!("Failed to notify {}: {err:?}", from.id);
+  }
+}
+
+/// Whether `user` is listed in `exempt_users`, by numeric id or username.
+fn is_exempt(exempt_users: &[String], user: &User) -> bool {
+  let id = user.id.to_string();
+  exempt_users.iter().any(|exempt| {
+    *exempt == id
+      || user
+        .username
+        .as_ref()
+        .is_some_and(|username| exempt.trim_start_matches('@') == username)
+  })
+}
+
+/// Whether `text` contains any of the opt-out `markers`, ignoring case.
+fn is_ignored(markers: &[String], text: &str) -> bool {
+  let text = text.to_lowercase();
+  markers
+    .iter()
+    .any(|marker| !marker.is_empty() && text.contains(&marker.to_lowercase()))
+}
+
+/// The formatted `{user}` of a repost, the channel's title for channel posts which have no sender.
+fn author(from: Option<&User>, sender_chat: Option<&Chat>, formatter: &dyn Formatter) -> String {
+  let mut user = String::new();
+  match (from, sender_chat.and_then(|chat| chat.title.as_deref())) {
+    (Some(from), _) => write_user(&mut user, from, formatter),
+    (None, Some(title)) => user.push_str(&formatter.escape(title)),
+    (None, None) => user.push_str("Unknown"),
+  }
+  user
+}
+
+/// Renders the cleaned repost of the already formatted `html` and `user`, escaping everything else
+/// user-controlled.
+///
+/// With `flag_affiliate`, notes when affiliate tags were removed, as the sender may have shared
+/// the link for a commission.
+fn render_repost(
+  template: &MessageTemplate,
+  user: &str,
+  html: &str,
+  outcome: &ReplaceOutcome,
+  forwarded: &str,
+  flag_affiliate: bool,
+  formatter: &dyn Formatter,
+) -> String {
+  let urls = cleaned_urls(outcome, formatter);
+  let mut text = render(
+    &template.template,
+    &[
+      ("user", user),
+      ("text", html),
+      ("urls", &urls),
+      ("forwarded", forwarded),
+    ],
+  );
+  if flag_affiliate && !outcome.affiliate_params.is_empty() {
+    let params = formatter.escape(&outcome.affiliate_params.join(", "));
+    text.push_str(&render(&template.affiliate, &[("params", &params)]));
+  }
+  text
+}
+
+/// The cleaned links of `outcome`, one per line.
+fn cleaned_urls(outcome: &ReplaceOutcome, formatter: &dyn Formatter) -> String {
+  outcome
+    .replacements
+    .iter()
+    .map(|replacement| formatter.escape(&replacement.replaced))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+/// What a repost's `{text}` holds in `mode`, given the cleaned and formatted `html`.
+fn repost_body(
+  mode: RepostMode,
+  html: String,
+  outcome: &ReplaceOutcome,
+  formatter: &dyn Formatter,
+) -> String {
+  match mode {
+    RepostMode::FullText => html,
+    RepostMode::LinksOnly => cleaned_urls(outcome, formatter),
+  }
+}
+
+/// Appends the configured `footer` to a repost, unless it would push an otherwise fitting
+/// `caption` over the caption limit.
+fn append_footer(text: &mut String, footer: &str, caption: bool, formatter: &dyn Formatter) {
+  if footer.is_empty() {
+    return;
+  }
+  let len = formatter.visible_len(text);
+  // A caption already too long is sent as a text message, where the footer fits.
+  if caption && len <= CAPTION_LIMIT && len + formatter.visible_len(footer) > CAPTION_LIMIT {
+    return;
+  }
+  text.push_str(footer);
+}
+
+/// Splits `text` into the command and its arguments if it is `/<command>`, optionally addressed
+/// as `/<command>@bot`, where `bot` must be `username` as commands to other bots in the chat are
+/// theirs.
+fn parse_command<'a>(text: &'a str, username: &str) -> Option<(&'a str, &'a str)> {
+  let text = text.strip_prefix('/')?;
+  let (command, args) = text.split_at(text.find(char::is_whitespace).unwrap_or(text.len()));
+  let command = match command.split_once('@') {
+    Some((command, bot)) if bot.eq_ignore_ascii_case(username) => command,
+    Some(_) => return None,
+    None => command,
+  };
+  if command.is_empty() {
+    return None;
+  }
+  Some((command, args.trim()))
+}
+
+/// Replies with what the cleaned text would be, without reposting or deleting anything.
+async fn preview(api: &AsyncApi, shared: &Shared, msg: &Message, args: &str) -> Result<()> {
+  let text = if args.is_empty() {
+    "Usage: /preview &lt;text&gt;".to_string()
+  } else {
+    let outcome = replace_all(args, &HashSet::new())
+      .await
+      .context("Failed to replace text")?;
+    let mut text = String::with_capacity(128);
+    write!(
+      text,
+      "Original:\n<pre>{}</pre>\nCleaned:\n<pre>{}</pre>",
+      v_htmlescape::escape(args),
+      v_htmlescape::escape(&outcome.text),
+    )
+    .unwrap();
+    text
+  };
+  if shared.dry_run {
+    info!("Dry run, would reply to /preview with:\n{text}");
+    return Ok(());
   }
 
   let mut send_msg = SendMessageParams::builder()
@@ -107,31 +982,207 @@ pub(crate) async fn process_update(
     .text(text)
     .parse_mode(ParseMode::Html)
     .build();
+  send_msg.reply_parameters = Some(
+    ReplyParameters::builder()
+      .message_id(msg.message_id)
+      .build(),
+  );
+
+  let resp = api
+    .send_message(&send_msg)
+    .await
+    .context("Failed to send preview...")?;
+  debug!("{resp:?}");
+  Ok(())
+}
+
+/// Replies with the replacement counters, to exempt users and chat admins only.
+async fn stats(api: &AsyncApi, config: &Config, shared: &Shared, msg: &Message) -> Result<()> {
+  let Some(from) = msg.from.as_deref() else {
+    return Ok(());
+  };
+  if !is_exempt(&config.exempt_users, from) && !is_chat_admin(api, msg.chat.id, from.id).await {
+    debug!("User {} is not allowed to see stats", from.id);
+    return Ok(());
+  }
 
-  send_msg.reply_parameters = msg
-    .reply_to_message
-    .map(|i| ReplyParameters::builder().message_id(i.message_id).build());
+  let uptime = Duration::from_secs(now_secs().saturating_sub(start_time()));
+  if shared.dry_run {
+    info!("Dry run, would reply to /stats");
+    return Ok(());
+  }
+  let mut send_msg = SendMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .text(shared.stats.summary(uptime))
+    .build();
+  send_msg.reply_parameters = Some(
+    ReplyParameters::builder()
+      .message_id(msg.message_id)
+      .build(),
+  );
 
   let resp = api
     .send_message(&send_msg)
     .await
-    .context("Failed to send message...")?;
+    .context("Failed to send stats...")?;
   debug!("{resp:?}");
+  Ok(())
+}
+
+/// Replies with the users whose messages were cleaned most, to exempt users and chat admins only.
+async fn leaderboard(
+  api: &AsyncApi,
+  config: &Config,
+  shared: &Shared,
+  msg: &Message,
+) -> Result<()> {
+  let Some(from) = msg.from.as_deref() else {
+    return Ok(());
+  };
+  if !is_exempt(&config.exempt_users, from) && !is_chat_admin(api, msg.chat.id, from.id).await {
+    debug!("User {} is not allowed to see the leaderboard", from.id);
+    return Ok(());
+  }
+
+  if shared.dry_run {
+    info!("Dry run, would reply to /leaderboard");
+    return Ok(());
+  }
+  let mut send_msg = SendMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .text(shared.leaderboard.summary())
+    .build();
+  send_msg.reply_parameters = Some(
+    ReplyParameters::builder()
+      .message_id(msg.message_id)
+      .build(),
+  );
 
   let resp = api
-    .delete_message(
-      &DeleteMessageParams::builder()
-        .chat_id(msg.chat.id)
-        .message_id(msg.message_id)
-        .build(),
-    )
+    .send_message(&send_msg)
     .await
-    .context("Failed to delete message...")?;
-  debug!("{resp:?}",);
+    .context("Failed to send leaderboard...")?;
+  debug!("{resp:?}");
+  Ok(())
+}
 
+/// Enables or disables cleaning in the chat of `msg`, to exempt users and chat admins only.
+async fn set_enabled(
+  api: &AsyncApi,
+  config: &Config,
+  shared: &Shared,
+  msg: &Message,
+  enable: bool,
+) -> Result<()> {
+  let Some(from) = msg.from.as_deref() else {
+    return Ok(());
+  };
+  if !is_exempt(&config.exempt_users, from) && !is_chat_admin(api, msg.chat.id, from.id).await {
+    debug!(
+      "User {} is not allowed to enable or disable the bot",
+      from.id
+    );
+    return Ok(());
+  }
+
+  let text = match shared.chats.set(msg.chat.id, enable) {
+    Ok(()) if enable => "Enabled cleaning links in this chat".to_string(),
+    Ok(()) => "Disabled cleaning links in this chat".to_string(),
+    Err(err) => {
+      error!("Failed to save chats: {err:?}");
+      format!("Failed to save chats: {err:#}")
+    },
+  };
+  if shared.dry_run {
+    info!("Dry run, would reply to {} with: {text}", msg.chat.id);
+    return Ok(());
+  }
+  let mut send_msg = SendMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .text(text)
+    .build();
+  send_msg.reply_parameters = Some(
+    ReplyParameters::builder()
+      .message_id(msg.message_id)
+      .build(),
+  );
+
+  let resp = api
+    .send_message(&send_msg)
+    .await
+    .context("Failed to send enable result...")?;
+  debug!("{resp:?}");
   Ok(())
 }
 
+/// Reloads the config file like SIGHUP does, to exempt users and chat admins only.
+async fn reload(api: &AsyncApi, config: &Config, shared: &Shared, msg: &Message) -> Result<()> {
+  let Some(from) = msg.from.as_deref() else {
+    return Ok(());
+  };
+  if !is_exempt(&config.exempt_users, from) && !is_chat_admin(api, msg.chat.id, from.id).await {
+    debug!("User {} is not allowed to reload config", from.id);
+    return Ok(());
+  }
+
+  let text = match crate::reload::reload(shared) {
+    Ok(restart_only) => reload_reply(&restart_only),
+    Err(err) => {
+      error!("Failed to reload config, keeping the current one: {err:?}");
+      format!("Failed to reload config, keeping the current one: {err:#}")
+    },
+  };
+  if shared.dry_run {
+    info!("Dry run, would reply to /reload with: {text}");
+    return Ok(());
+  }
+  let mut send_msg = SendMessageParams::builder()
+    .chat_id(msg.chat.id)
+    .text(text)
+    .build();
+  send_msg.reply_parameters = Some(
+    ReplyParameters::builder()
+      .message_id(msg.message_id)
+      .build(),
+  );
+
+  let resp = api
+    .send_message(&send_msg)
+    .await
+    .context("Failed to send reload result...")?;
+  debug!("{resp:?}");
+  Ok(())
+}
+
+/// Reply to a successful `/reload`, naming the changed settings that still need a restart.
+fn reload_reply(restart_only: &[&str]) -> String {
+  if restart_only.is_empty() {
+    "Config reloaded".to_string()
+  } else {
+    format!(
+      "Config reloaded, but changes to {} only apply after a restart",
+      restart_only.join(", ")
+    )
+  }
+}
+
+async fn is_chat_admin(api: &AsyncApi, chat_id: i64, user_id: u64) -> bool {
+  let params = GetChatAdministratorsParams::builder()
+    .chat_id(chat_id)
+    .build();
+  match api.get_chat_administrators(&params).await {
+    Ok(resp) => resp.result.iter().any(|member| match member {
+      ChatMember::Creator(owner) => owner.user.id == user_id,
+      ChatMember::Administrator(admin) => admin.user.id == user_id,
+      _ => false,
+    }),
+    Err(err) => {
+      debug!("Failed to get administrators of {chat_id}: {err}");
+      false
+    },
+  }
+}
+
 struct MessageType(UpdateContent);
 
 impl Display for MessageType {
@@ -164,3 +1215,530 @@ impl Display for MessageType {
     f.write_str(str)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use frankenstein::{ChatType, MessageOriginChannel, MessageOriginChat, TextQuote};
+  use fuckburl_bot::replacer::Replacement;
+
+  use super::*;
+  use crate::markup::Html;
+
+  #[tokio::test]
+  async fn limit_concurrent_updates() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let permits = Arc::new(Semaphore::new(2));
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let mut tasks = JoinSet::new();
+    for _ in 0..5 {
+      let (permits, running, peak) = (permits.clone(), running.clone(), peak.clone());
+      tasks.spawn(async move {
+        with_permit(&permits, async {
+          let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+          peak.fetch_max(now, Ordering::SeqCst);
+          tokio::time::sleep(Duration::from_millis(10)).await;
+          running.fetch_sub(1, Ordering::SeqCst);
+          Ok(())
+        })
+        .await
+      });
+    }
+    while let Some(result) = tasks.join_next().await {
+      result.unwrap().unwrap();
+    }
+    assert_eq!(2, peak.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn exempt_by_id_or_username() {
+    let exempt = vec!["114514".to_string(), "@feed_bot".to_string()];
+    let by_id = User::builder()
+      .id(114514)
+      .is_bot(false)
+      .first_name("Alice".to_string())
+      .build();
+    let by_username = User::builder()
+      .id(1919810)
+      .is_bot(true)
+      .first_name("Feed".to_string())
+      .username("feed_bot".to_string())
+      .build();
+    let other = User::builder()
+      .id(42)
+      .is_bot(false)
+      .first_name("Bob".to_string())
+      .username("bob".to_string())
+      .build();
+    assert!(is_exempt(&exempt, &by_id));
+    assert!(is_exempt(&exempt, &by_username));
+    assert!(!is_exempt(&exempt, &other));
+  }
+
+  #[test]
+  fn ignore_markers() {
+    let markers = vec!["@ignoreme".to_string(), "#Raw".to_string()];
+    assert!(is_ignored(&markers, "https://b23.tv/abc @IgnoreMe"));
+    assert!(is_ignored(
+      &markers,
+      "keep this one #raw https://b23.tv/abc"
+    ));
+    assert!(!is_ignored(&markers, "https://b23.tv/abc"));
+    assert!(!is_ignored(&[String::new()], "https://b23.tv/abc"));
+  }
+
+  #[test]
+  fn render_repost_template() {
+    let template = MessageTemplate {
+      template: "{user} 分享了：\n{text}\n\n{urls}{forwarded}".to_string(),
+      ..Default::default()
+    };
+    let user = User::builder()
+      .id(114514)
+      .is_bot(false)
+      .first_name("<Alice>".to_string())
+      .build();
+    let outcome = ReplaceOutcome {
+      text: "look https://example.com/?a=1&b=2".to_string(),
+      changed: true,
+      replacements: vec![Replacement {
+        platform: Platform::Twitter,
+        original: "https://example.com/?a=1&b=2&utm_source=x".to_string(),
+        replaced: "https://example.com/?a=1&b=2".to_string(),
+      }],
+      affiliate_params: Vec::new(),
+    };
+    assert_eq!(
+      "<a href=\"tg://user?id=114514\">&lt;Alice&gt;</a> 分享了：\nlook https:&#x2f;&#x2f;example.com&#x2f;?a=1&amp;b=2\n\nhttps:&#x2f;&#x2f;example.com&#x2f;?a=1&amp;b=2",
+      render_repost(
+        &template,
+        &author(Some(&user), None, &Html),
+        &v_htmlescape::escape(&outcome.text).to_string(),
+        &outcome,
+        "",
+        false,
+        &Html
+      )
+    );
+  }
+
+  #[test]
+  fn forwarded_from_chat() {
+    let template = MessageTemplate::default();
+    let channel = Chat::builder()
+      .id(-100123)
+      .type_field(ChatType::Channel)
+      .title("<News>".to_string())
+      .build();
+    let from_channel = MessageOrigin::Channel(
+      MessageOriginChannel::builder()
+        .date(0)
+        .chat(Box::new(channel))
+        .message_id(1)
+        .build(),
+    );
+    assert_eq!(
+      Some("\n\n<i>forwarded from &lt;News&gt;</i>".to_string()),
+      render_forwarded(&template, Some(&from_channel), false, &Html)
+    );
+    assert_eq!(
+      None,
+      render_forwarded(&template, Some(&from_channel), true, &Html)
+    );
+
+    let group = Chat::builder()
+      .id(-100456)
+      .type_field(ChatType::Supergroup)
+      .username("rust_zh".to_string())
+      .build();
+    let from_group = MessageOrigin::Chat(
+      MessageOriginChat::builder()
+        .date(0)
+        .sender_chat(Box::new(group))
+        .build(),
+    );
+    assert_eq!(
+      Some("\n\n<i>forwarded from rust_zh</i>".to_string()),
+      render_forwarded(&template, Some(&from_group), false, &Html)
+    );
+    assert_eq!(
+      Some(String::new()),
+      render_forwarded(&template, None, true, &Html)
+    );
+  }
+
+  #[test]
+  fn channel_post_author() {
+    let channel = Chat::builder()
+      .id(-100123)
+      .type_field(ChatType::Channel)
+      .title("<News>".to_string())
+      .build();
+    assert_eq!("&lt;News&gt;", author(None, Some(&channel), &Html));
+    assert_eq!("Unknown", author(None, None, &Html));
+
+    let template = MessageTemplate::default();
+    assert_eq!(
+      "Send by &lt;News&gt;:\n\nhttps:&#x2f;&#x2f;b23.tv&#x2f;abc",
+      render_repost(
+        &template,
+        &author(None, Some(&channel), &Html),
+        "https:&#x2f;&#x2f;b23.tv&#x2f;abc",
+        &ReplaceOutcome {
+          text: String::new(),
+          changed: true,
+          replacements: Vec::new(),
+          affiliate_params: Vec::new(),
+        },
+        "",
+        false,
+        &Html
+      )
+    );
+  }
+
+  #[test]
+  fn flag_affiliate_params() {
+    let template = MessageTemplate {
+      template: "{text}".to_string(),
+      ..Default::default()
+    };
+    let outcome = ReplaceOutcome {
+      text: "https://www.amazon.com/dp/B0C1234567/".to_string(),
+      changed: true,
+      replacements: Vec::new(),
+      affiliate_params: vec!["tag".to_string()],
+    };
+    assert_eq!(
+      "text\n\n<i>(affiliate tag removed)</i>",
+      render_repost(&template, "", "text", &outcome, "", true, &Html)
+    );
+    assert_eq!(
+      "text",
+      render_repost(&template, "", "text", &outcome, "", false, &Html)
+    );
+  }
+
+  #[test]
+  fn append_message_footer() {
+    let footer = "\n\n<i>cleaned by @mybot</i>";
+    let mut text = "text".to_string();
+    append_footer(&mut text, footer, false, &Html);
+    assert_eq!("text\n\n<i>cleaned by @mybot</i>", text);
+
+    let mut text = "text".to_string();
+    append_footer(&mut text, "", false, &Html);
+    assert_eq!("text", text);
+
+    let almost_full = "a".repeat(CAPTION_LIMIT - 4);
+    let mut caption = almost_full.clone();
+    append_footer(&mut caption, footer, true, &Html);
+    assert_eq!(almost_full, caption);
+    let mut text = almost_full.clone();
+    append_footer(&mut text, footer, false, &Html);
+    assert_eq!(format!("{almost_full}{footer}"), text);
+  }
+
+  #[test]
+  fn text_or_caption() {
+    let message = |text: Option<&str>, caption: Option<&str>| {
+      Message::builder()
+        .message_id(1)
+        .date(0)
+        .chat(Box::new(
+          Chat::builder().id(1).type_field(ChatType::Group).build(),
+        ))
+        .maybe_text(text.map(str::to_string))
+        .maybe_caption(caption.map(str::to_string))
+        .build()
+    };
+    let text = message(Some("https://b23.tv/abc"), None);
+    assert_eq!(Some(("https://b23.tv/abc", None)), message_text(&text));
+    let video = message(None, Some("clip https://b23.tv/abc"));
+    assert_eq!(
+      Some(("clip https://b23.tv/abc", None)),
+      message_text(&video)
+    );
+    assert_eq!(None, message_text(&message(None, None)));
+  }
+
+  #[test]
+  fn quote_of_replied_message() {
+    let message = |text: &str, quote: Option<&str>, replied: Option<Message>| {
+      Message::builder()
+        .message_id(1)
+        .date(0)
+        .chat(Box::new(
+          Chat::builder().id(1).type_field(ChatType::Group).build(),
+        ))
+        .text(text.to_string())
+        .maybe_quote(quote.map(|quote| {
+          Box::new(
+            TextQuote::builder()
+              .text(quote.to_string())
+              .position(0)
+              .build(),
+          )
+        }))
+        .maybe_reply_to_message(replied.map(Box::new))
+        .build()
+    };
+    let replied = || {
+      Some(message(
+        "see https://b23.tv/abc and https://youtu.be/xyz?si=123",
+        None,
+        None,
+      ))
+    };
+
+    assert_eq!(None, quote_text(&message("agreed", None, replied())));
+    assert_eq!(
+      Some(("https://b23.tv/abc", None)),
+      quote_text(&message("agreed", Some("https://b23.tv/abc"), replied()))
+    );
+    // Cut short inside both links
+    assert_eq!(
+      Some(("https://b23.tv/abc and https://youtu.be/xyz?si=123", None)),
+      quote_text(&message(
+        "agreed",
+        Some("23.tv/abc and https://youtu"),
+        replied()
+      ))
+    );
+    // Quoting a message of another chat
+    assert_eq!(
+      Some(("https://youtu", None)),
+      quote_text(&message("agreed", Some("https://youtu"), None))
+    );
+  }
+
+  #[test]
+  fn edit_own_messages() {
+    let message = |from: u64| {
+      Message::builder()
+        .message_id(1)
+        .date(0)
+        .chat(Box::new(
+          Chat::builder().id(1).type_field(ChatType::Group).build(),
+        ))
+        .from(Box::new(
+          User::builder()
+            .id(from)
+            .is_bot(true)
+            .first_name("Bot".to_string())
+            .build(),
+        ))
+        .build()
+    };
+    assert!(is_own(&message(114514), 114514));
+    assert!(!is_own(&message(1919810), 114514));
+  }
+
+  #[test]
+  fn disable_link_preview() {
+    let original = Message::builder()
+      .message_id(1)
+      .date(0)
+      .chat(Box::new(
+        Chat::builder().id(1).type_field(ChatType::Group).build(),
+      ))
+      .build();
+    let config = |extra: &str| {
+      crate::parse_config(
+        &format!("telegram-token = \"114514:token\"\nenabled-chats = []\n{extra}"),
+        false,
+      )
+      .unwrap()
+    };
+
+    let params = send_params(&config(""), &original, "text".to_string(), None);
+    assert_eq!(None, params.link_preview_options);
+    let params = send_params(
+      &config("disable-web-page-preview = true"),
+      &original,
+      "text".to_string(),
+      Some("https://b23.tv/abc"),
+    );
+    assert_eq!(
+      Some(true),
+      params
+        .link_preview_options
+        .and_then(|options| options.is_disabled)
+    );
+  }
+
+  #[test]
+  fn preview_single_cleaned_url() {
+    let replacement = |replaced: &str| Replacement {
+      platform: Platform::Twitter,
+      original: format!("{replaced}?s=20"),
+      replaced: replaced.to_string(),
+    };
+    let mut outcome = ReplaceOutcome {
+      text: String::new(),
+      changed: true,
+      replacements: vec![replacement("https://twitter.com/a/status/1")],
+      affiliate_params: Vec::new(),
+    };
+    let url = primary_url(&outcome);
+    assert_eq!(Some("https://twitter.com/a/status/1"), url);
+
+    let original = Message::builder()
+      .message_id(1)
+      .date(0)
+      .chat(Box::new(
+        Chat::builder().id(1).type_field(ChatType::Group).build(),
+      ))
+      .build();
+    let config = crate::parse_config(
+      "telegram-token = \"114514:token\"\nenabled-chats = []",
+      false,
+    )
+    .unwrap();
+    let params = send_params(&config, &original, "text".to_string(), url);
+    assert_eq!(
+      Some("https://twitter.com/a/status/1".to_string()),
+      params.link_preview_options.and_then(|options| options.url)
+    );
+
+    outcome
+      .replacements
+      .push(replacement("https://twitter.com/b/status/2"));
+    assert_eq!(None, primary_url(&outcome));
+  }
+
+  #[test]
+  fn minor_change_ratio() {
+    let link = "https://twitter.com/a/status/1?s=20";
+    let outcome = ReplaceOutcome {
+      text: String::new(),
+      changed: true,
+      replacements: vec![Replacement {
+        platform: Platform::Twitter,
+        original: link.to_string(),
+        replaced: "https://twitter.com/a/status/1".to_string(),
+      }],
+      affiliate_params: Vec::new(),
+    };
+    let short = format!("look {link}");
+    let long = format!("{}\n{link}", "a long story ".repeat(20));
+    assert!(!is_minor_change(&short, &outcome, 0.5));
+    assert!(is_minor_change(&long, &outcome, 0.5));
+    assert!(!is_minor_change(&long, &outcome, 0.0));
+  }
+
+  #[test]
+  fn repost_modes() {
+    let outcome = ReplaceOutcome {
+      text: String::new(),
+      changed: true,
+      replacements: vec![
+        Replacement {
+          platform: Platform::Twitter,
+          original: "https://twitter.com/a/status/1?s=20".to_string(),
+          replaced: "https://twitter.com/a/status/1".to_string(),
+        },
+        Replacement {
+          platform: Platform::Amazon,
+          original: "https://www.amazon.com/dp/B0?tag=a&b=1".to_string(),
+          replaced: "https://www.amazon.com/dp/B0".to_string(),
+        },
+      ],
+      affiliate_params: Vec::new(),
+    };
+    let html = "look &lt;here&gt; https://twitter.com/a/status/1".to_string();
+    assert_eq!(
+      html,
+      repost_body(RepostMode::FullText, html.clone(), &outcome, &Html)
+    );
+    assert_eq!(
+      "https:&#x2f;&#x2f;twitter.com&#x2f;a&#x2f;status&#x2f;1\nhttps:&#x2f;&#x2f;www.amazon.com&#x2f;dp&#x2f;B0",
+      repost_body(RepostMode::LinksOnly, html, &outcome, &Html)
+    );
+  }
+
+  #[test]
+  fn skip_other_bots() {
+    let message = |from: u64, is_bot: bool| {
+      Message::builder()
+        .message_id(1)
+        .date(0)
+        .chat(Box::new(
+          Chat::builder().id(1).type_field(ChatType::Group).build(),
+        ))
+        .from(Box::new(
+          User::builder()
+            .id(from)
+            .is_bot(is_bot)
+            .first_name("Bot".to_string())
+            .build(),
+        ))
+        .build()
+    };
+    assert!(is_other_bot(&message(1919810, true), 114514));
+    assert!(!is_other_bot(&message(114514, true), 114514));
+    assert!(!is_other_bot(&message(1919810, false), 114514));
+  }
+
+  #[test]
+  fn repost_in_same_topic() {
+    let message = |is_topic_message: Option<bool>| {
+      Message::builder()
+        .message_id(10)
+        .message_thread_id(7)
+        .maybe_is_topic_message(is_topic_message)
+        .date(0)
+        .chat(Box::new(
+          Chat::builder()
+            .id(-100123)
+            .type_field(ChatType::Supergroup)
+            .is_forum(true)
+            .build(),
+        ))
+        .build()
+    };
+    assert_eq!(Some(7), topic_thread_id(&message(Some(true))));
+    assert_eq!(None, topic_thread_id(&message(None)));
+  }
+
+  #[test]
+  fn parse_preview_command() {
+    assert_eq!(
+      Some(("preview", "https://b23.tv/abc")),
+      parse_command("/preview https://b23.tv/abc", "fuckburl_bot")
+    );
+    assert_eq!(
+      Some(("preview", "https://b23.tv/abc")),
+      parse_command("/preview@FuckBurl_Bot  https://b23.tv/abc ", "fuckburl_bot")
+    );
+    assert_eq!(
+      Some(("preview", "")),
+      parse_command("/preview", "fuckburl_bot")
+    );
+    assert_eq!(
+      Some(("previews", "abc")),
+      parse_command("/previews abc", "fuckburl_bot")
+    );
+    assert_eq!(None, parse_command("preview abc", "fuckburl_bot"));
+    assert_eq!(None, parse_command("/ abc", "fuckburl_bot"));
+  }
+
+  #[test]
+  fn reload_reply_names_restart_only_changes() {
+    assert_eq!("Config reloaded", reload_reply(&[]));
+    assert_eq!(
+      "Config reloaded, but changes to proxy, webhook only apply after a restart",
+      reload_reply(&["proxy", "webhook"])
+    );
+  }
+
+  #[test]
+  fn ignore_commands_to_other_bots() {
+    assert_eq!(
+      None,
+      parse_command("/preview@otherbot https://b23.tv/abc", "fuckburl_bot")
+    );
+    assert_eq!(None, parse_command("/reload@otherbot", "fuckburl_bot"));
+  }
+}
@@ -0,0 +1,92 @@
+use std::{
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use anyhow::{Context, Result};
+use axum::{http::StatusCode, routing::get, Router};
+use log::info;
+use tokio::net::TcpListener;
+
+use crate::{now_secs, start_time, Shared};
+
+/// When updates were last fetched, in seconds since the epoch.
+#[derive(Debug, Default)]
+pub(crate) struct LastPoll(AtomicU64);
+
+impl LastPoll {
+  pub(crate) fn touch(&self) {
+    self.0.store(now_secs(), Ordering::Relaxed);
+  }
+
+  fn age(&self) -> Duration {
+    Duration::from_secs(now_secs().saturating_sub(self.0.load(Ordering::Relaxed)))
+  }
+}
+
+/// Serves `/healthz`, failing once updates weren't fetched for `max_poll_age`.
+///
+/// `max_poll_age` is `None` with webhooks, where nothing is polled and a quiet chat looks the
+/// same as a dead one.
+pub(crate) async fn serve(
+  listen_addr: SocketAddr,
+  username: String,
+  shared: Arc<Shared>,
+  max_poll_age: Option<Duration>,
+) -> Result<()> {
+  let listener = TcpListener::bind(listen_addr)
+    .await
+    .with_context(|| format!("Failed to listen on {listen_addr}"))?;
+  info!("Serving health checks on {listen_addr}");
+  let app = Router::new().route(
+    "/healthz",
+    get(move || async move { check(&username, &shared.last_poll, max_poll_age) }),
+  );
+  axum::serve(listener, app)
+    .await
+    .context("Health check server exited unexpectedly")
+}
+
+fn check(
+  username: &str,
+  last_poll: &LastPoll,
+  max_poll_age: Option<Duration>,
+) -> (StatusCode, String) {
+  let uptime = now_secs().saturating_sub(start_time());
+  let mut body = format!("bot: @{username}\nuptime: {uptime}s");
+  let Some(max_poll_age) = max_poll_age else {
+    return (StatusCode::OK, body);
+  };
+  let age = last_poll.age();
+  body.push_str(&format!("\nlast poll: {}s ago", age.as_secs()));
+  if age > max_poll_age {
+    (StatusCode::SERVICE_UNAVAILABLE, body)
+  } else {
+    (StatusCode::OK, body)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn stale_poll_is_unhealthy() {
+    let last_poll = LastPoll::default();
+    let max_poll_age = Some(Duration::from_secs(60));
+    assert_eq!(
+      StatusCode::SERVICE_UNAVAILABLE,
+      check("bot", &last_poll, max_poll_age).0
+    );
+    last_poll.touch();
+    let (status, body) = check("bot", &last_poll, max_poll_age);
+    assert_eq!(StatusCode::OK, status);
+    assert!(body.starts_with("bot: @bot\nuptime: "));
+
+    assert_eq!(StatusCode::OK, check("bot", &LastPoll::default(), None).0);
+  }
+}
@@ -0,0 +1,107 @@
+use std::{collections::HashMap, fmt::Write, sync::Mutex};
+
+use frankenstein::User;
+use serde::{Deserialize, Serialize};
+
+/// How many posters `/leaderboard` lists.
+const LEADERBOARD_SIZE: usize = 10;
+
+/// A user whose messages were cleaned, persisted with `persist-leaderboard`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Poster {
+  pub(crate) id: u64,
+  /// Display name as of the last cleaned message
+  pub(crate) name: String,
+  pub(crate) username: Option<String>,
+  pub(crate) cleaned: u64,
+}
+
+/// Cleaned messages per user, keyed by user id as names and usernames change.
+#[derive(Debug, Default)]
+pub(crate) struct Leaderboard(Mutex<HashMap<u64, Poster>>);
+
+impl Leaderboard {
+  /// Restores the counters saved in the state file.
+  pub(crate) fn restore(&self, posters: Vec<Poster>) {
+    let mut map = self.0.lock().unwrap();
+    map.extend(posters.into_iter().map(|poster| (poster.id, poster)));
+  }
+
+  /// Counts a cleaned message of `user`, updating the stored name.
+  pub(crate) fn record(&self, user: &User) {
+    let name = match &user.last_name {
+      Some(last) => format!("{} {last}", user.first_name),
+      None => user.first_name.clone(),
+    };
+    let mut map = self.0.lock().unwrap();
+    let poster = map.entry(user.id).or_insert_with(|| Poster {
+      id: user.id,
+      name: String::new(),
+      username: None,
+      cleaned: 0,
+    });
+    poster.name = name;
+    poster.username.clone_from(&user.username);
+    poster.cleaned += 1;
+  }
+
+  /// All counters, sorted by id so the state file stays stable.
+  pub(crate) fn posters(&self) -> Vec<Poster> {
+    let mut posters = self.0.lock().unwrap().values().cloned().collect::<Vec<_>>();
+    posters.sort_by_key(|poster| poster.id);
+    posters
+  }
+
+  /// Renders the top posters for the `/leaderboard` command.
+  pub(crate) fn summary(&self) -> String {
+    let mut posters = self.posters();
+    if posters.is_empty() {
+      return "No links cleaned yet".to_string();
+    }
+    posters.sort_by(|a, b| b.cleaned.cmp(&a.cleaned).then_with(|| a.id.cmp(&b.id)));
+    let mut text = String::from("Most tracking links posted:");
+    for (rank, poster) in posters.iter().take(LEADERBOARD_SIZE).enumerate() {
+      write!(text, "\n{}. {}", rank + 1, poster.name).unwrap();
+      if let Some(username) = &poster.username {
+        write!(text, " (@{username})").unwrap();
+      }
+      write!(text, ": {}", poster.cleaned).unwrap();
+    }
+    text
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn user(id: u64, first_name: &str, username: Option<&str>) -> User {
+    User::builder()
+      .id(id)
+      .is_bot(false)
+      .first_name(first_name.to_string())
+      .maybe_username(username.map(str::to_string))
+      .build()
+  }
+
+  #[test]
+  fn ranks_by_cleaned_messages() {
+    let leaderboard = Leaderboard::default();
+    assert_eq!("No links cleaned yet", leaderboard.summary());
+
+    let alice = user(1, "Alice", Some("alice"));
+    let bob = user(2, "Bob", None);
+    leaderboard.record(&alice);
+    leaderboard.record(&bob);
+    leaderboard.record(&bob);
+    assert_eq!(
+      "Most tracking links posted:\n1. Bob: 2\n2. Alice (@alice): 1",
+      leaderboard.summary()
+    );
+
+    let restored = Leaderboard::default();
+    restored.restore(leaderboard.posters());
+    assert_eq!(leaderboard.posters(), restored.posters());
+  }
+}
@@ -0,0 +1,3 @@
+//! Link cleaning used by the bot, usable on its own without the Telegram plumbing.
+
+pub mod replacer;
@@ -1,5 +1,21 @@
+mod chats;
+mod clean_file;
+mod entities;
 mod event;
-mod replacer;
+mod health;
+mod leaderboard;
+mod markup;
+mod media_group;
+mod message;
+mod metrics;
+mod quiet_hours;
+mod ratelimit;
+mod reload;
+mod retry;
+mod state;
+mod stats;
+mod tg_error;
+mod webhook;
 
 use frankenstein::reqwest::{Client, Proxy};
 use log::{debug, info, LevelFilter};
@@ -8,42 +24,352 @@ use log4rs::{
   config::{Appender, Root},
   encode::pattern::PatternEncoder,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
 use std::{
+  collections::{HashMap, HashSet},
   fs::{self, File},
   io::{BufReader, BufWriter, Read, Write},
-  path::PathBuf,
+  net::SocketAddr,
+  path::{Path, PathBuf},
   process,
-  sync::{Arc, OnceLock},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, OnceLock,
+  },
   time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, ValueHint};
+use arc_swap::ArcSwap;
+use clap::{Parser, Subcommand, ValueHint};
 use clap_verbosity_flag::{LogLevel, Verbosity, VerbosityFilter};
-use frankenstein::{AllowedUpdate, AsyncApi, AsyncTelegramApi, GetUpdatesParams};
+use frankenstein::{
+  AllowedUpdate, AsyncApi, AsyncTelegramApi, DeleteWebhookParams, GetUpdatesParams,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use fuckburl_bot::replacer::{
+  self, ClientOptions, CustomRule, KeepParams, Platform, ReplaceOptions, BILI_KEPT_KEYS,
+};
 
-use crate::event::process_update;
+use crate::{
+  chats::RuntimeChats, event::spawn_process_update, health::LastPoll, leaderboard::Leaderboard,
+  markup::Markup, media_group::MediaGroups, quiet_hours::QuietHours, ratelimit::RateLimiter,
+  state::State, stats::Stats,
+};
 
 #[derive(Parser, Debug)]
 struct Cli {
   #[arg(short = 'c', long, value_name = "DIR")]
   #[arg(value_hint = ValueHint::FilePath)]
   config_file: Option<PathBuf>,
+  /// Clean messages and log the results, without sending or deleting anything
+  #[arg(long)]
+  dry_run: bool,
+  /// Validate the config file and exit, without connecting to telegram
+  #[arg(long)]
+  check_config: bool,
   #[clap(flatten)]
   verbose: Verbosity<DefaultLevel>,
+  #[command(subcommand)]
+  command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Clean each line of a file with the default rules and print the results, no config needed
+  CleanFile {
+    #[arg(value_hint = ValueHint::FilePath)]
+    path: PathBuf,
+  },
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 struct Config {
   telegram_token: String,
+  /// Base url of a local Bot API server, e.g. `http://localhost:8081`
+  api_base_url: Option<String>,
   #[serde(default = "Default::default")]
-  enabled_chats: Vec<String>,
+  enabled_chats: Vec<EnabledChat>,
+  /// Senders never cleaned, by username or numeric id
+  #[serde(default = "Default::default")]
+  exempt_users: Vec<String>,
+  /// Messages containing any of these, case-insensitively, are never cleaned
+  #[serde(default = "default_ignore_markers", deserialize_with = "one_or_many")]
+  ignore_marker: Vec<String>,
   proxy: Option<String>,
+  /// Proxy for resolving short links, defaults to `proxy`
+  redirect_proxy: Option<String>,
+  /// User agent for resolving short links, defaults to a desktop Chrome
+  user_agent: Option<String>,
+  /// Timeout for resolving a single short link
+  #[serde(default = "default_redirect_timeout_ms")]
+  redirect_timeout_ms: u64,
+  /// How long a resolved short link is reused, `0` disables caching
+  #[serde(default = "default_redirect_cache_ttl_secs")]
+  redirect_cache_ttl_secs: u64,
+  /// Redirects followed for short links of these hosts, instead of one
+  #[serde(default)]
+  redirect_hops: HashMap<String, usize>,
+  /// Passes over short links that redirect to further short links
+  #[serde(default = "default_max_resolve_iterations")]
+  max_resolve_iterations: usize,
+  /// Keep `xsec_token` on xiaohongshu links, some notes don't load without it
+  #[serde(default)]
+  xhs_keep_token: bool,
+  /// Query keys kept per platform, e.g. `youtube = ["v", "t"]`, and per section for bilibili
+  #[serde(default)]
+  keep_params: HashMap<Platform, KeepParams>,
+  /// Platforms switched off in every chat, e.g. `amazon = false`
+  #[serde(default)]
+  replacers: HashMap<Platform, bool>,
+  /// Stripped from links of any site, besides `utm_source` and the like
+  #[serde(default)]
+  extra_trackers: Vec<String>,
+  /// Links to these domains and their subdomains are never touched
+  #[serde(default)]
+  domain_blocklist: Vec<String>,
+  /// Trackers are also stripped from the fragment of links to these domains and their subdomains
+  #[serde(default)]
+  fragment_tracker_domains: Vec<String>,
+  /// Site rules applied after the built-in ones
+  #[serde(default)]
+  custom_rules: Vec<CustomRule>,
   #[serde(default = "Default::default")]
   time: Time,
+  #[serde(default = "Default::default")]
+  retry: Retry,
+  /// Updates processed at once, more wait for their turn
+  #[serde(default = "default_max_concurrent_updates")]
+  max_concurrent_updates: usize,
+  /// Reposts sent per chat per second, `0` disables limiting
+  #[serde(default = "default_max_msgs_per_sec")]
+  max_msgs_per_sec: u32,
+  /// Markup of reposts and the `[message]` templates
+  #[serde(default)]
+  parse_mode: Markup,
+  #[serde(default = "Default::default")]
+  message: MessageTemplate,
+  /// What the `{text}` of reposts holds
+  #[serde(default)]
+  repost_mode: RepostMode,
+  /// Appended to every repost as is, left out of captions it would make too long
+  #[serde(default)]
+  message_footer: String,
+  /// Note in reposts when affiliate tags, e.g. Amazon's `tag`, were removed
+  #[serde(default)]
+  flag_affiliate: bool,
+  /// Tell senders their link was cleaned, by DM or else a reply in the chat
+  #[serde(default)]
+  notify_original_sender: bool,
+  /// Send reposts without a link preview
+  #[serde(default)]
+  disable_web_page_preview: bool,
+  /// Disable chats the bot was removed from once posting there fails
+  #[serde(default)]
+  auto_prune_dead_chats: bool,
+  /// Also clean messages sent by other bots, which may repost cleaned links back and forth
+  #[serde(default)]
+  process_bot_messages: bool,
+  /// Also clean messages edited after being sent
+  #[serde(default)]
+  handle_edits: bool,
+  /// Delete edited originals too, instead of replying to them
+  #[serde(default)]
+  delete_edited: bool,
+  /// Also clean posts of enabled channels, the bot must be an admin there
+  #[serde(default)]
+  enable_channels: bool,
+  /// Leave messages forwarded from channels and groups alone, as before
+  #[serde(default)]
+  skip_forwarded_channels: bool,
+  /// Reply with just the cleaned links, keeping the original, when they take up less of it
+  #[serde(default)]
+  min_change_ratio: f64,
+  /// Delete the original message, otherwise the cleaned copy replies to it
+  #[serde(default = "default_true")]
+  delete_original: bool,
+  /// Wait this long after reposting before deleting the original
+  #[serde(default)]
+  delete_delay_ms: u64,
+  /// Nothing is cleaned in this window, unless a chat sets its own
+  quiet_hours: Option<QuietHours>,
+  webhook: Option<Webhook>,
+  metrics: Option<Metrics>,
+  health: Option<Health>,
+  /// Defaults to `state.toml` next to the config file
+  state_file: Option<PathBuf>,
+  /// Where `/enable` and `/disable` are persisted, defaults to `chats.toml` next to the config file
+  chats_file: Option<PathBuf>,
+  /// Keep `/leaderboard` counters, with user names, in the state file across restarts
+  #[serde(default)]
+  persist_leaderboard: bool,
+}
+
+fn default_true() -> bool {
+  true
+}
+
+fn default_ignore_markers() -> Vec<String> {
+  vec!["@ignoreme".to_string()]
+}
+
+/// Accepts either a single string or a list of them.
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum Repr {
+    One(String),
+    Many(Vec<String>),
+  }
+
+  Ok(match Repr::deserialize(deserializer)? {
+    Repr::One(one) => vec![one],
+    Repr::Many(many) => many,
+  })
+}
+
+fn default_redirect_timeout_ms() -> u64 {
+  5000
+}
+
+fn default_redirect_cache_ttl_secs() -> u64 {
+  3600
+}
+
+fn default_max_resolve_iterations() -> usize {
+  3
+}
+
+fn default_max_concurrent_updates() -> usize {
+  32
+}
+
+fn default_max_msgs_per_sec() -> u32 {
+  1
+}
+
+/// Runtime state shared by all update tasks.
+#[derive(Debug)]
+struct Shared {
+  /// Swapped on reload, see [`Shared::config`]
+  config: ArcSwap<Config>,
+  /// Where the config is reloaded from
+  config_path: PathBuf,
+  /// Chats enabled or disabled with commands
+  chats: RuntimeChats,
+  /// User id of the bot, whose own messages are edited instead of reposted
+  bot_id: u64,
+  /// Username of the bot, commands addressed to other bots are ignored
+  username: String,
+  limiter: RateLimiter,
+  /// Bounds the updates processed at once, replaced when a reload changes the bound
+  update_permits: ArcSwap<Semaphore>,
+  /// Chats already warned about missing delete permission
+  delete_warned: Mutex<HashSet<i64>>,
+  media_groups: MediaGroups,
+  stats: Stats,
+  leaderboard: Leaderboard,
+  metrics: metrics::Handles,
+  last_poll: LastPoll,
+  /// Log messages instead of sending or deleting them
+  dry_run: bool,
+}
+
+impl Shared {
+  fn new(
+    config: Arc<Config>,
+    config_path: PathBuf,
+    chats: RuntimeChats,
+    bot_id: u64,
+    username: String,
+    dry_run: bool,
+  ) -> Self {
+    Self {
+      limiter: RateLimiter::new(config.max_msgs_per_sec),
+      update_permits: ArcSwap::from_pointee(Semaphore::new(config.max_concurrent_updates.max(1))),
+      delete_warned: Mutex::new(HashSet::new()),
+      media_groups: MediaGroups::default(),
+      stats: Stats::default(),
+      leaderboard: Leaderboard::default(),
+      metrics: metrics::Handles::new(),
+      last_poll: LastPoll::default(),
+      dry_run,
+      config: ArcSwap::new(config),
+      config_path,
+      chats,
+      bot_id,
+      username,
+    }
+  }
+
+  /// The current config, updates keep the one they started with even across a reload.
+  fn config(&self) -> Arc<Config> {
+    self.config.load_full()
+  }
+
+  /// The current bound on updates cleaning or sending at once.
+  fn update_permits(&self) -> Arc<Semaphore> {
+    self.update_permits.load_full()
+  }
+}
+
+/// An enabled chat, either a bare name/id or `{ id = "...", disable = ["twitter"] }`.
+#[derive(Debug, PartialEq)]
+struct EnabledChat {
+  id: String,
+  disable: HashSet<Platform>,
+  /// Overrides the global quiet hours
+  quiet_hours: Option<QuietHours>,
+}
+
+impl EnabledChat {
+  /// A chat with every platform enabled and the global quiet hours.
+  fn new(id: String) -> Self {
+    Self {
+      id,
+      disable: HashSet::new(),
+      quiet_hours: None,
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for EnabledChat {
+  fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+      Id(String),
+      Detailed {
+        id: String,
+        #[serde(default)]
+        disable: HashSet<Platform>,
+        #[serde(rename = "quiet-hours")]
+        quiet_hours: Option<QuietHours>,
+      },
+    }
+
+    Ok(match Repr::deserialize(deserializer)? {
+      Repr::Id(id) => EnabledChat::new(id),
+      Repr::Detailed {
+        id,
+        disable,
+        quiet_hours,
+      } => EnabledChat {
+        id,
+        disable,
+        quiet_hours,
+      },
+    })
+  }
 }
 
 #[derive(Debug, Deserialize)]
@@ -63,16 +389,145 @@ impl Default for Time {
   }
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct Retry {
+  max_attempts: u32,
+  base_delay_ms: u64,
+}
+
+impl Default for Retry {
+  fn default() -> Self {
+    Self {
+      max_attempts: 3,
+      base_delay_ms: 500,
+    }
+  }
+}
+
+/// What reposts hold in place of the original message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RepostMode {
+  /// The whole cleaned text
+  #[default]
+  FullText,
+  /// Only the cleaned links, one per line
+  LinksOnly,
+}
+
+/// Templates of reposted messages, in the configured `parse-mode`.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all(deserialize = "kebab-case"))]
+struct MessageTemplate {
+  /// Supports `{user}`, `{text}`, `{urls}` and `{forwarded}`
+  template: String,
+  /// Rendered into `{forwarded}` for forwarded messages, supports `{user}`
+  forwarded: String,
+  /// Appended with `flag-affiliate` when affiliate tags were removed, supports `{params}`
+  affiliate: String,
+  /// Sent to the original sender with `notify-original-sender`
+  notice: String,
+}
+
+impl MessageTemplate {
+  fn default_for(markup: Markup) -> Self {
+    let formatter = markup.formatter();
+    Self {
+      template: "Send by {user}:\n\n{text}{forwarded}".to_string(),
+      forwarded: format!("\n\n{}", formatter.italic("forwarded from {user}")),
+      affiliate: format!(
+        "\n\n{}",
+        formatter.italic(&formatter.escape("(affiliate tag removed)"))
+      ),
+      notice: formatter.escape("Your link was cleaned to remove tracking."),
+    }
+  }
+
+  /// Swaps templates left at their HTML default for the default in `markup`.
+  fn adapt(&mut self, markup: Markup) {
+    let (html, adapted) = (Self::default(), Self::default_for(markup));
+    for (template, html, adapted) in [
+      (&mut self.template, html.template, adapted.template),
+      (&mut self.forwarded, html.forwarded, adapted.forwarded),
+      (&mut self.affiliate, html.affiliate, adapted.affiliate),
+      (&mut self.notice, html.notice, adapted.notice),
+    ] {
+      if *template == html {
+        *template = adapted;
+      }
+    }
+  }
+}
+
+impl Default for MessageTemplate {
+  fn default() -> Self {
+    Self::default_for(Markup::Html)
+  }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct Webhook {
+  url: String,
+  listen_addr: SocketAddr,
+  secret_token: String,
+  /// Short links still resolving this long after an update arrives are left as they are
+  #[serde(default = "default_resolve_deadline_ms")]
+  resolve_deadline_ms: u64,
+}
+
+fn default_resolve_deadline_ms() -> u64 {
+  10000
+}
+
+impl Webhook {
+  /// Whether serving the webhook differs, which only applies after a restart.
+  fn needs_restart(this: Option<&Self>, other: Option<&Self>) -> bool {
+    fn served(webhook: &Webhook) -> (&str, SocketAddr, &str) {
+      (&webhook.url, webhook.listen_addr, &webhook.secret_token)
+    }
+    this.map(served) != other.map(served)
+  }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct Metrics {
+  listen_addr: SocketAddr,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct Health {
+  listen_addr: SocketAddr,
+  /// Unhealthy once updates weren't fetched for this long, ignored with webhooks
+  #[serde(default = "default_max_poll_age_secs")]
+  max_poll_age_secs: u64,
+}
+
+fn default_max_poll_age_secs() -> u64 {
+  60
+}
+
 static START_TIME: OnceLock<u64> = OnceLock::new();
 
 fn start_time() -> u64 {
-  *START_TIME.get_or_init(|| {
-    let start = SystemTime::now();
-    let since_the_epoch = start
-      .duration_since(UNIX_EPOCH)
-      .expect("Time went backwards");
-    since_the_epoch.as_secs()
-  })
+  *START_TIME.get_or_init(now_secs)
+}
+
+fn now_secs() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards")
+    .as_secs()
+}
+
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+/// Whether polling resumed from a persisted offset, so no stale updates are replayed.
+fn resumed() -> bool {
+  RESUMED.load(Ordering::Relaxed)
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -81,10 +536,31 @@ async fn main() -> Result<()> {
   init_logger(args.verbose.log_level_filter());
   info!("Start at: {:?}", start_time());
   debug!("{args:?}");
-  let config = init_config(args.config_file).context("Failed to init config file")?;
+  if let Some(Command::CleanFile { path }) = &args.command {
+    return clean_file::run(path).await;
+  }
+  let config_path = config_path(args.config_file)?;
+  if args.check_config {
+    return check_config(&config_path);
+  }
+  let config = init_config(&config_path).context("Failed to init config file")?;
+  validate_config(&config)?;
   let config = Arc::new(config);
   debug!("{config:?}");
 
+  if let Some(server) = &config.metrics {
+    let handle = metrics::install()?;
+    let listen_addr = server.listen_addr;
+    tokio::spawn(async move {
+      if let Err(err) = metrics::serve(listen_addr, handle).await {
+        log::error!("{err:?}");
+      }
+    });
+  }
+
+  replacer::set_redirect_client(&client_options(&config))?;
+  replacer::set_replace_options(replace_options(&config));
+
   let mut cli = Client::builder();
   if let Some(proxy) = &config.proxy {
     let proxy =
@@ -94,10 +570,9 @@ async fn main() -> Result<()> {
   let cli = cli.build()?;
 
   let tg_api = AsyncApi::builder()
-    .api_url(format!(
-      "{}{}",
-      frankenstein::BASE_API_URL,
-      &*config.telegram_token,
+    .api_url(api_url(
+      config.api_base_url.as_deref(),
+      &config.telegram_token,
     ))
     .client(cli.clone())
     .build();
@@ -106,47 +581,244 @@ async fn main() -> Result<()> {
     .get_me()
     .await
     .context("Failed to get telegram bot self info")?;
-  info!(
-    "Current tg bot: {}",
-    me.result
-      .username
-      .context("Failed to get username for bot, maybe token is invalid")?
-  );
+  let bot_id = me.result.id;
+  let username = me
+    .result
+    .username
+    .context("Failed to get username for bot, maybe token is invalid")?;
+  info!("Current tg bot: {username}");
+
+  if args.dry_run {
+    info!("Dry run, nothing will be sent or deleted");
+  }
+  let chats_file = config
+    .chats_file
+    .clone()
+    .context("Chats file path is not set")?;
+  let chats = RuntimeChats::load(chats_file).context("Failed to load chats")?;
+  let shared = Arc::new(Shared::new(
+    Arc::clone(&config),
+    config_path,
+    chats,
+    bot_id,
+    username.clone(),
+    args.dry_run,
+  ));
+  tokio::spawn(reload::watch(Arc::clone(&shared)));
+
+  if let Some(health) = &config.health {
+    let listen_addr = health.listen_addr;
+    let max_poll_age = config
+      .webhook
+      .is_none()
+      .then(|| Duration::from_secs(health.max_poll_age_secs));
+    shared.last_poll.touch();
+    let shared = Arc::clone(&shared);
+    tokio::spawn(async move {
+      if let Err(err) = health::serve(listen_addr, username, shared, max_poll_age).await {
+        log::error!("{err:?}");
+      }
+    });
+  }
+
+  let state_file = config
+    .state_file
+    .clone()
+    .context("State file path is not set")?;
+  let mut state = State::load(&state_file).context("Failed to load state")?;
+  if config.persist_leaderboard {
+    shared
+      .leaderboard
+      .restore(std::mem::take(&mut state.leaderboard));
+  }
+
+  if config.webhook.is_some() {
+    webhook::serve(tg_api, config, Arc::clone(&shared)).await?;
+    // Nothing is polled, so the leaderboard is only saved on shutdown.
+    save_state(&mut state, &state_file, &shared);
+    return Ok(());
+  }
+
+  tg_api
+    .delete_webhook(&DeleteWebhookParams::builder().build())
+    .await
+    .context("Failed to delete telegram webhook")?;
+
+  if let Some(offset) = state.offset {
+    info!("Resuming updates from offset {offset}");
+    RESUMED.store(true, Ordering::Relaxed);
+  }
 
   let mut update_params = GetUpdatesParams::builder()
-    .allowed_updates(vec![AllowedUpdate::Message])
+    .allowed_updates(allowed_updates(&config))
+    .maybe_offset(state.offset)
     .build();
 
+  let shutdown = shutdown_signal();
+  tokio::pin!(shutdown);
+  let mut tasks = JoinSet::new();
+
   loop {
-    let result = tg_api.get_updates(&update_params).await;
+    let result = tokio::select! {
+      _ = &mut shutdown => break,
+      result = tg_api.get_updates(&update_params) => result,
+    };
     match result {
       Ok(response) => {
+        shared.last_poll.touch();
         if let Some(last) = response.result.last() {
+          let offset = last.update_id as i64 + 1;
           update_params = GetUpdatesParams::builder()
-            .allowed_updates(vec![AllowedUpdate::Message])
-            .offset(last.update_id as i64 + 1)
+            .allowed_updates(allowed_updates(&shared.config()))
+            .offset(offset)
             .build();
+          state.offset = Some(offset);
+          save_state(&mut state, &state_file, &shared);
         }
 
+        while tasks.try_join_next().is_some() {}
         for update in response.result {
-          let api = Arc::clone(&tg_api);
-          let config = Arc::clone(&config);
-          tokio::spawn(async move {
-            let result = process_update(&api, config, update)
-              .await
-              .with_context(|| "Failed to process update".to_string());
-            if let Err(err) = result {
-              log::error!("{err:?}");
-            }
-          });
+          spawn_process_update(
+            &mut tasks,
+            Arc::clone(&tg_api),
+            shared.config(),
+            Arc::clone(&shared),
+            update,
+          );
         }
       },
       Err(error) => {
         log::error!("Failed to get updates: {error:?}");
-        tokio::time::sleep(Duration::from_millis(config.time.failed_delay)).await;
+        tokio::select! {
+          _ = &mut shutdown => break,
+          _ = tokio::time::sleep(Duration::from_millis(shared.config().time.failed_delay)) => {},
+        }
       },
     }
   }
+
+  info!("Shutting down...");
+  drain_tasks(tasks).await;
+  if shared.config().persist_leaderboard {
+    save_state(&mut state, &state_file, &shared);
+  }
+  Ok(())
+}
+
+/// Persists the update offset, and the leaderboard with `persist-leaderboard`, dropping a
+/// previously saved one without.
+fn save_state(state: &mut State, path: &Path, shared: &Shared) {
+  state.leaderboard = if shared.config().persist_leaderboard {
+    shared.leaderboard.posters()
+  } else {
+    Vec::new()
+  };
+  if let Err(err) = state.save(path) {
+    log::error!("Failed to persist state: {err:?}");
+  }
+}
+
+/// Completes on Ctrl-C, or SIGTERM on unix.
+async fn shutdown_signal() {
+  let ctrl_c = async {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+      log::error!("Failed to listen for Ctrl-C: {err}");
+      std::future::pending::<()>().await;
+    }
+  };
+
+  #[cfg(unix)]
+  let terminate = async {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+      Ok(mut signal) => {
+        signal.recv().await;
+      },
+      Err(err) => {
+        log::error!("Failed to listen for SIGTERM: {err}");
+        std::future::pending::<()>().await;
+      },
+    }
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = ctrl_c => {},
+    _ = terminate => {},
+  }
+}
+
+/// Waits a bounded time for in-flight update tasks, so no delete is left half-done.
+async fn drain_tasks(mut tasks: JoinSet<()>) {
+  const GRACE_PERIOD: Duration = Duration::from_secs(10);
+  if tasks.is_empty() {
+    return;
+  }
+  info!("Waiting for {} in-flight updates...", tasks.len());
+  let drained = tokio::time::timeout(GRACE_PERIOD, async {
+    while tasks.join_next().await.is_some() {}
+  })
+  .await;
+  if drained.is_err() {
+    log::warn!(
+      "Gave up waiting for {} updates after {GRACE_PERIOD:?}",
+      tasks.len()
+    );
+  }
+}
+
+fn allowed_updates(config: &Config) -> Vec<AllowedUpdate> {
+  let mut updates = vec![AllowedUpdate::Message];
+  if config.handle_edits {
+    updates.push(AllowedUpdate::EditedMessage);
+  }
+  if config.enable_channels {
+    updates.push(AllowedUpdate::ChannelPost);
+    if config.handle_edits {
+      updates.push(AllowedUpdate::EditedChannelPost);
+    }
+  }
+  updates
+}
+
+/// Settings of the client resolving short links with `config`, rebuilt when a reload changes them.
+fn client_options(config: &Config) -> ClientOptions {
+  ClientOptions {
+    user_agent: config
+      .user_agent
+      .clone()
+      .unwrap_or_else(|| replacer::DEFAULT_USER_AGENT.to_string()),
+    timeout: Duration::from_millis(config.redirect_timeout_ms),
+    cache_ttl: Duration::from_secs(config.redirect_cache_ttl_secs),
+    redirect_hops: config.redirect_hops.clone(),
+    proxy: config
+      .redirect_proxy
+      .clone()
+      .or_else(|| config.proxy.clone()),
+  }
+}
+
+/// How links are cleaned with `config`, set again on every reload.
+fn replace_options(config: &Config) -> ReplaceOptions {
+  ReplaceOptions {
+    xhs_keep_token: config.xhs_keep_token,
+    keep_params: config.keep_params.clone(),
+    max_resolve_iterations: config.max_resolve_iterations,
+    custom_rules: config.custom_rules.clone(),
+    extra_trackers: config.extra_trackers.clone(),
+    domain_blocklist: config.domain_blocklist.clone(),
+    fragment_tracker_domains: config.fragment_tracker_domains.clone(),
+    replacers: config.replacers.clone(),
+  }
+}
+
+/// The Bot API url of `token`, on the official server unless `base_url` is set.
+fn api_url(base_url: Option<&str>, token: &str) -> String {
+  match base_url {
+    Some(base_url) => format!("{}/bot{token}", base_url.trim_end_matches('/')),
+    None => format!("{}{token}", frankenstein::BASE_API_URL),
+  }
 }
 
 #[cfg(debug_assertions)]
@@ -176,8 +848,8 @@ fn init_logger(verbosity: LevelFilter) {
   log4rs::init_config(config).unwrap();
 }
 
-fn init_config(path: Option<PathBuf>) -> Result<Config> {
-  let path = if let Some(dir) = path {
+fn config_path(path: Option<PathBuf>) -> Result<PathBuf> {
+  Ok(if let Some(dir) = path {
     dir
   } else if cfg!(debug_assertions) {
     std::env::current_dir()
@@ -188,42 +860,96 @@ fn init_config(path: Option<PathBuf>) -> Result<Config> {
     std::env::current_dir()
       .context("Failed to get current dir")?
       .join("config.toml")
-  };
+  })
+}
+
+/// Reads and validates the config at `path` for `--check-config`, a missing one is an error
+/// instead of being created.
+fn check_config(path: &Path) -> Result<()> {
+  let config = read_config(path)?;
+  validate_config(&config)?;
+  println!("Config is valid: {}", path.to_string_lossy());
+  Ok(())
+}
+
+/// Whether `token` looks like `<bot id>:<secret>` as issued by @BotFather.
+fn is_valid_token(token: &str) -> bool {
+  token.split_once(':').is_some_and(|(id, secret)| {
+    !id.is_empty()
+      && id.bytes().all(|b| b.is_ascii_digit())
+      && !secret.is_empty()
+      && secret
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+  })
+}
+
+/// Checks what parsing the config doesn't, reporting every problem at once. Custom rule patterns
+/// are already compiled by parsing.
+fn validate_config(config: &Config) -> Result<()> {
+  let mut problems = Vec::new();
+  if !is_valid_token(&config.telegram_token) {
+    problems.push("telegram-token is not in the form <bot id>:<secret>".to_string());
+  }
+  for (key, proxy) in [
+    ("proxy", &config.proxy),
+    ("redirect-proxy", &config.redirect_proxy),
+  ] {
+    if let Some(proxy) = proxy {
+      if let Err(err) = Proxy::all(proxy.as_str()) {
+        problems.push(format!("{key} \"{proxy}\" is invalid: {err}"));
+      }
+    }
+  }
+  for (platform, keep) in &config.keep_params {
+    match (platform, keep) {
+      (Platform::Bilibili, KeepParams::Keys(_)) => problems.push(
+        "keep-params.bilibili must list keys per section, e.g. { video = [\"p\", \"t\"] }"
+          .to_string(),
+      ),
+      (Platform::Bilibili, KeepParams::Sections(sections)) => {
+        for section in sections.keys() {
+          if !BILI_KEPT_KEYS.iter().any(|(name, _)| name == section) {
+            problems.push(format!(
+              "keep-params.bilibili has unknown section \"{section}\""
+            ));
+          }
+        }
+      },
+      (_, KeepParams::Keys(_)) => {},
+      (platform, KeepParams::Sections(_)) => {
+        problems.push(format!("keep-params.{platform} must be a list of keys"))
+      },
+    }
+  }
+  if problems.is_empty() {
+    Ok(())
+  } else {
+    bail!("Invalid config:\n  {}", problems.join("\n  "))
+  }
+}
 
+fn init_config(path: &Path) -> Result<Config> {
   info!("Initializing config file...");
 
   if path.exists() && path.is_file() {
-    info!("Reading config from {}...", &path.to_string_lossy());
-    let file = File::open(&path).context("Failed to")?;
-    let mut buf_reader = BufReader::new(file);
-    let mut config_str = String::new();
-    buf_reader
-      .read_to_string(&mut config_str)
-      .with_context(|| {
-        format!(
-          "Failed to read config file as String: {}",
-          &path.to_string_lossy()
-        )
-      })?;
-    let config: Config = toml::from_str(&config_str)
-      .with_context(|| format!("Failed to parse config file: {}", &path.to_string_lossy()))?;
-    Ok(config)
+    read_config(path)
   } else if !path.exists() {
     if let Some(parent) = path.parent() {
       fs::create_dir_all(parent)
         .with_context(|| format!("Failed to create folder: {}", parent.to_string_lossy()))?;
     };
-    let config = File::create(&path).with_context(|| {
+    let config = File::create(path).with_context(|| {
       format!(
         "Failed to create default config: {}",
         &path.to_string_lossy()
       )
     })?;
-    const DEFAULT_CONFIG: &[u8] = include_bytes!("config.example.toml");
+    let default_config = default_config(is_json(path))?;
 
     {
       let mut buf_writer = BufWriter::new(config);
-      buf_writer.write_all(DEFAULT_CONFIG).with_context(|| {
+      buf_writer.write_all(&default_config).with_context(|| {
         format!(
           "Failed to write default config to: {}",
           &path.to_string_lossy()
@@ -237,3 +963,278 @@ fn init_config(path: Option<PathBuf>) -> Result<Config> {
     bail!("Path is not a file: {}", path.to_string_lossy())
   }
 }
+
+/// Configs ending in `.json` are JSON, anything else TOML.
+fn is_json(path: &Path) -> bool {
+  path
+    .extension()
+    .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+fn parse_config(str: &str, json: bool) -> Result<Config> {
+  let mut config: Config = if json {
+    serde_json::from_str(str)?
+  } else {
+    toml::from_str(str)?
+  };
+  config.message.adapt(config.parse_mode);
+  Ok(config)
+}
+
+/// The example config, converted to JSON without its comments when `json`.
+fn default_config(json: bool) -> Result<Vec<u8>> {
+  const DEFAULT_CONFIG: &str = include_str!("config.example.toml");
+  if !json {
+    return Ok(DEFAULT_CONFIG.as_bytes().to_vec());
+  }
+  let value: toml::Value =
+    toml::from_str(DEFAULT_CONFIG).context("Failed to parse example config")?;
+  serde_json::to_vec_pretty(&value).context("Failed to convert example config to JSON")
+}
+
+fn read_config(path: &Path) -> Result<Config> {
+  info!("Reading config from {}...", &path.to_string_lossy());
+  let file = File::open(path).context("Failed to")?;
+  let mut buf_reader = BufReader::new(file);
+  let mut config_str = String::new();
+  buf_reader
+    .read_to_string(&mut config_str)
+    .with_context(|| {
+      format!(
+        "Failed to read config file as String: {}",
+        &path.to_string_lossy()
+      )
+    })?;
+  let mut config = parse_config(&config_str, is_json(path))
+    .with_context(|| format!("Failed to parse config file: {}", &path.to_string_lossy()))?;
+  if config.state_file.is_none() {
+    config.state_file = Some(path.with_file_name("state.toml"));
+  }
+  if config.chats_file.is_none() {
+    config.chats_file = Some(path.with_file_name("chats.toml"));
+  }
+  Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_enabled_chats() {
+    let config: Config = toml::from_str(
+      r#"
+telegram-token = "114514:token"
+enabled-chats = ["group_name", { id = "-100123", disable = ["twitter", "youtube"] }]
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+      vec![
+        EnabledChat {
+          id: "group_name".to_string(),
+          disable: HashSet::new(),
+          quiet_hours: None,
+        },
+        EnabledChat {
+          id: "-100123".to_string(),
+          disable: HashSet::from([Platform::Twitter, Platform::Youtube]),
+          quiet_hours: None,
+        },
+      ],
+      config.enabled_chats
+    );
+  }
+
+  #[test]
+  fn parse_quiet_hours() {
+    let config: Config = toml::from_str(
+      r#"
+telegram-token = "114514:token"
+enabled-chats = ["group_name", { id = "-100123", quiet-hours = { start = "01:00", end = "07:00" } }]
+
+[quiet-hours]
+start = "22:00"
+end = "06:00"
+"#,
+    )
+    .unwrap();
+    assert!(config.quiet_hours.is_some());
+    assert!(config.enabled_chats[0].quiet_hours.is_none());
+    assert_ne!(config.quiet_hours, config.enabled_chats[1].quiet_hours);
+    assert!(config.enabled_chats[1].quiet_hours.is_some());
+  }
+
+  #[test]
+  fn parse_json_config() {
+    let toml = parse_config(
+      r#"
+telegram-token = "114514:token"
+enabled-chats = ["group_name", { id = "-100123", disable = ["twitter"] }]
+delete-original = false
+
+[quiet-hours]
+start = "22:00"
+end = "06:00"
+"#,
+      false,
+    )
+    .unwrap();
+    let json = parse_config(
+      r#"{
+  "telegram-token": "114514:token",
+  "enabled-chats": ["group_name", { "id": "-100123", "disable": ["twitter"] }],
+  "delete-original": false,
+  "quiet-hours": { "start": "22:00", "end": "06:00" }
+}"#,
+      true,
+    )
+    .unwrap();
+    assert_eq!(format!("{toml:?}"), format!("{json:?}"));
+
+    assert!(is_json(Path::new("config.JSON")));
+    assert!(!is_json(Path::new("config.toml")));
+    let example = String::from_utf8(default_config(true).unwrap()).unwrap();
+    assert!(parse_config(&example, true).is_ok());
+  }
+
+  #[test]
+  fn markdown_v2_message_defaults() {
+    let config = parse_config(
+      r#"
+telegram-token = "114514:token"
+enabled-chats = []
+parse-mode = "markdown-v2"
+
+[message]
+template = "*{user}*: {text}"
+"#,
+      false,
+    )
+    .unwrap();
+    assert_eq!(
+      MessageTemplate {
+        template: "*{user}*: {text}".to_string(),
+        forwarded: "\n\n_forwarded from {user}_".to_string(),
+        affiliate: "\n\n_\\(affiliate tag removed\\)_".to_string(),
+        notice: "Your link was cleaned to remove tracking\\.".to_string(),
+      },
+      config.message
+    );
+  }
+
+  #[test]
+  fn parse_ignore_marker() {
+    let config: Config = toml::from_str(r#"telegram-token = "114514:token""#).unwrap();
+    assert_eq!(vec!["@ignoreme".to_string()], config.ignore_marker);
+
+    let config: Config = toml::from_str(
+      r##"
+telegram-token = "114514:token"
+ignore-marker = "#raw"
+"##,
+    )
+    .unwrap();
+    assert_eq!(vec!["#raw".to_string()], config.ignore_marker);
+
+    let config: Config = toml::from_str(
+      r##"
+telegram-token = "114514:token"
+ignore-marker = ["#raw", "#keep"]
+"##,
+    )
+    .unwrap();
+    assert_eq!(
+      vec!["#raw".to_string(), "#keep".to_string()],
+      config.ignore_marker
+    );
+  }
+
+  #[test]
+  fn reject_bad_custom_rule() {
+    let err = toml::from_str::<Config>(
+      r#"
+telegram-token = "114514:token"
+
+[[custom-rules]]
+pattern = '(unclosed'
+replacement = "$1"
+"#,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("invalid pattern"));
+  }
+
+  #[test]
+  fn compose_api_url() {
+    assert_eq!(
+      "https://api.telegram.org/bot114514:token",
+      api_url(None, "114514:token")
+    );
+    assert_eq!(
+      "http://localhost:8081/bot114514:token",
+      api_url(Some("http://localhost:8081/"), "114514:token")
+    );
+  }
+
+  #[test]
+  fn validate_token_and_proxies() {
+    let config =
+      |extra: &str| parse_config(&format!("enabled-chats = []\n{extra}"), false).unwrap();
+    assert!(validate_config(&config(
+      r#"telegram-token = "114514:AAH-fake_token"
+proxy = "socks5://127.0.0.1:7890""#
+    ))
+    .is_ok());
+
+    let err = validate_config(&config(
+      r#"telegram-token = "fake_token"
+redirect-proxy = "http://[::1""#,
+    ))
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("telegram-token"));
+    assert!(err.contains("redirect-proxy"));
+    assert!(!is_valid_token(":secret"));
+    assert!(!is_valid_token("114514:"));
+  }
+
+  #[test]
+  fn parse_keep_params() {
+    let config: Config = toml::from_str(
+      r#"
+telegram-token = "114514:token"
+
+[keep-params]
+youtube = ["v", "t"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+      HashMap::from([(
+        Platform::Youtube,
+        KeepParams::Keys(vec!["v".to_string(), "t".to_string()])
+      )]),
+      config.keep_params
+    );
+  }
+
+  #[test]
+  fn validate_bilibili_keep_params() {
+    let config = |keep: &str| {
+      parse_config(
+        &format!("telegram-token = \"114514:token\"\n[keep-params]\n{keep}"),
+        false,
+      )
+      .unwrap()
+    };
+    assert!(validate_config(&config(r#"bilibili = { video = ["p"], live = [] }"#)).is_ok());
+    for invalid in [
+      r#"bilibili = ["p"]"#,
+      r#"bilibili = { vidoe = ["p"] }"#,
+      r#"youtube = { video = ["v"] }"#,
+    ] {
+      assert!(validate_config(&config(invalid)).is_err(), "{invalid}");
+    }
+  }
+}
@@ -0,0 +1,188 @@
+use frankenstein::ParseMode;
+use serde::Deserialize;
+
+use crate::message::{markdown_v2_len, split_html, split_markdown_v2, visible_len};
+
+/// How reposts are formatted, set by `parse-mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Markup {
+  #[default]
+  Html,
+  MarkdownV2,
+}
+
+impl Markup {
+  pub(crate) fn formatter(self) -> &'static dyn Formatter {
+    match self {
+      Markup::Html => &Html,
+      Markup::MarkdownV2 => &MarkdownV2,
+    }
+  }
+}
+
+/// Writes text in one of telegram's parse modes.
+pub(crate) trait Formatter: Send + Sync {
+  fn parse_mode(&self) -> ParseMode;
+
+  /// `text` escaped to show as is.
+  fn escape(&self, text: &str) -> String;
+
+  /// `text` linked to `url`, both unescaped.
+  fn link(&self, text: &str, url: &str) -> String;
+
+  /// `name` linked to the user `id`, for users without a username.
+  fn mention(&self, name: &str, id: u64) -> String;
+
+  /// The already formatted `text` in italics.
+  fn italic(&self, text: &str) -> String;
+
+  /// The already formatted `text` as a quote.
+  fn blockquote(&self, text: &str) -> String;
+
+  /// Length of formatted `text` as telegram counts it.
+  fn visible_len(&self, text: &str) -> usize;
+
+  /// Splits formatted `text` into parts of at most `limit` characters, see
+  /// [`split_html`].
+  fn split(&self, text: &str, limit: usize) -> Vec<String>;
+}
+
+pub(crate) struct Html;
+
+impl Formatter for Html {
+  fn parse_mode(&self) -> ParseMode {
+    ParseMode::Html
+  }
+
+  fn escape(&self, text: &str) -> String {
+    v_htmlescape::escape(text).to_string()
+  }
+
+  fn link(&self, text: &str, url: &str) -> String {
+    format!(
+      r#"<a href="{}">{}</a>"#,
+      v_htmlescape::escape(url),
+      v_htmlescape::escape(text)
+    )
+  }
+
+  fn mention(&self, name: &str, id: u64) -> String {
+    format!(
+      r#"<a href="tg://user?id={id}">{}</a>"#,
+      v_htmlescape::escape(name)
+    )
+  }
+
+  fn italic(&self, text: &str) -> String {
+    format!("<i>{text}</i>")
+  }
+
+  fn blockquote(&self, text: &str) -> String {
+    format!("<blockquote>{text}</blockquote>")
+  }
+
+  fn visible_len(&self, text: &str) -> usize {
+    visible_len(text)
+  }
+
+  fn split(&self, text: &str, limit: usize) -> Vec<String> {
+    split_html(text, limit)
+  }
+}
+
+pub(crate) struct MarkdownV2;
+
+impl MarkdownV2 {
+  /// Escapes everything MarkdownV2 reserves, with `\`.
+  fn escape_chars(text: &str, reserved: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+      if c == '\\' || reserved.contains(c) {
+        escaped.push('\\');
+      }
+      escaped.push(c);
+    }
+    escaped
+  }
+}
+
+impl Formatter for MarkdownV2 {
+  fn parse_mode(&self) -> ParseMode {
+    ParseMode::MarkdownV2
+  }
+
+  fn escape(&self, text: &str) -> String {
+    Self::escape_chars(text, "_*[]()~`>#+-=|{}.!")
+  }
+
+  fn link(&self, text: &str, url: &str) -> String {
+    format!("[{}]({})", self.escape(text), Self::escape_chars(url, ")"))
+  }
+
+  fn mention(&self, name: &str, id: u64) -> String {
+    format!("[{}](tg://user?id={id})", self.escape(name))
+  }
+
+  fn italic(&self, text: &str) -> String {
+    format!("_{text}_")
+  }
+
+  fn blockquote(&self, text: &str) -> String {
+    text
+      .lines()
+      .map(|line| format!(">{line}"))
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+
+  fn visible_len(&self, text: &str) -> usize {
+    markdown_v2_len(text)
+  }
+
+  fn split(&self, text: &str, limit: usize) -> Vec<String> {
+    split_markdown_v2(text, limit)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const NAME: &str = "<A_l*i.c&e>";
+
+  #[test]
+  fn html_formatter() {
+    let html = Markup::Html.formatter();
+    assert_eq!(ParseMode::Html, html.parse_mode());
+    assert_eq!("&lt;A_l*i.c&amp;e&gt;", html.escape(NAME));
+    assert_eq!(
+      "<a href=\"tg://user?id=1\">&lt;A_l*i.c&amp;e&gt;</a>",
+      html.mention(NAME, 1)
+    );
+    assert_eq!(
+      "<a href=\"https:&#x2f;&#x2f;b23.tv&#x2f;?a=1&amp;b=2\">&lt;A_l*i.c&amp;e&gt;</a>",
+      html.link(NAME, "https://b23.tv/?a=1&b=2")
+    );
+    assert_eq!("<i>a</i>", html.italic("a"));
+    assert_eq!("<blockquote>a\nb</blockquote>", html.blockquote("a\nb"));
+  }
+
+  #[test]
+  fn markdown_v2_formatter() {
+    let markdown = Markup::MarkdownV2.formatter();
+    assert_eq!(ParseMode::MarkdownV2, markdown.parse_mode());
+    assert_eq!("<A\\_l\\*i\\.c&e\\>", markdown.escape(NAME));
+    assert_eq!(
+      "[<A\\_l\\*i\\.c&e\\>](tg://user?id=1)",
+      markdown.mention(NAME, 1)
+    );
+    assert_eq!(
+      "[a\\\\b](https://example.com/(x\\)?a=1&b=2)",
+      markdown.link("a\\b", "https://example.com/(x)?a=1&b=2")
+    );
+    assert_eq!("_a_", markdown.italic("a"));
+    assert_eq!(">a\n>b", markdown.blockquote("a\nb"));
+    assert_eq!(NAME.len(), markdown.visible_len(&markdown.escape(NAME)));
+  }
+}
@@ -0,0 +1,68 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use frankenstein::Message;
+
+/// How long the items of an album are collected after its first one arrives.
+pub(crate) const MEDIA_GROUP_WINDOW: Duration = Duration::from_secs(1);
+
+/// Buffers album items, which telegram delivers as separate messages sharing a `media_group_id`.
+#[derive(Debug, Default)]
+pub(crate) struct MediaGroups {
+  pending: Mutex<HashMap<String, Vec<Message>>>,
+}
+
+impl MediaGroups {
+  /// Buffers `msg`, returns whether it is the first item of its group.
+  pub(crate) fn push(&self, group_id: &str, msg: Message) -> bool {
+    let mut pending = self.pending.lock().unwrap();
+    let items = pending.entry(group_id.to_string()).or_default();
+    items.push(msg);
+    items.len() == 1
+  }
+
+  /// Removes the buffered items of a group, in the order they were sent.
+  pub(crate) fn take(&self, group_id: &str) -> Vec<Message> {
+    let mut items = self
+      .pending
+      .lock()
+      .unwrap()
+      .remove(group_id)
+      .unwrap_or_default();
+    items.sort_by_key(|msg| msg.message_id);
+    items
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use frankenstein::{Chat, ChatType};
+
+  use super::*;
+
+  fn message(message_id: i32) -> Message {
+    Message::builder()
+      .message_id(message_id)
+      .date(0)
+      .chat(Box::new(
+        Chat::builder().id(1).type_field(ChatType::Group).build(),
+      ))
+      .build()
+  }
+
+  #[test]
+  fn collects_group_in_order() {
+    let groups = MediaGroups::default();
+    assert!(groups.push("album", message(2)));
+    assert!(!groups.push("album", message(1)));
+    assert!(groups.push("other", message(3)));
+
+    let ids = groups
+      .take("album")
+      .iter()
+      .map(|msg| msg.message_id)
+      .collect::<Vec<_>>();
+    assert_eq!(vec![1, 2], ids);
+    assert!(groups.take("album").is_empty());
+    assert_eq!(1, groups.take("other").len());
+  }
+}
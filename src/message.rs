@@ -0,0 +1,285 @@
+/// Renders `{name}` placeholders in `template`, unknown placeholders are kept as is.
+///
+/// Values are inserted verbatim in a single pass, so placeholders inside values are never expanded.
+pub(crate) fn render(template: &str, vars: &[(&str, &str)]) -> String {
+  let mut out = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    out.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let value = rest.find('}').and_then(|end| {
+      let name = &rest[1..end];
+      vars
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| (*value, end))
+    });
+    match value {
+      Some((value, end)) => {
+        out.push_str(value);
+        rest = &rest[end + 1..];
+      },
+      None => {
+        out.push('{');
+        rest = &rest[1..];
+      },
+    }
+  }
+  out.push_str(rest);
+  out
+}
+
+/// Telegram's limit on the length of a message's text.
+pub(crate) const TEXT_LIMIT: usize = 4096;
+/// Telegram's limit on the length of a media caption.
+pub(crate) const CAPTION_LIMIT: usize = 1024;
+
+/// A piece of telegram HTML or MarkdownV2.
+enum Token<'a> {
+  /// `<b>` or `[`, taking no space
+  Open(&'a str),
+  /// `</b>` or `](url)`, taking no space
+  Close(&'a str),
+  /// `_` or `*`, opening and closing the same markup, taking no space
+  Toggle(&'a str),
+  /// `>` starting a quoted line, taking no space
+  Mark(&'a str),
+  /// `&amp;` and the like, rendered as a single character
+  Entity(&'a str),
+  /// `\_` and the like, rendered as the escaped character
+  Escaped(&'a str),
+  Char(char),
+}
+
+impl Token<'_> {
+  fn width(&self) -> usize {
+    match self {
+      Token::Open(_) | Token::Close(_) | Token::Toggle(_) | Token::Mark(_) => 0,
+      Token::Entity(_) => 1,
+      Token::Escaped(s) => s[1..].chars().map(char::len_utf16).sum(),
+      Token::Char(c) => c.len_utf16(),
+    }
+  }
+
+  fn len(&self) -> usize {
+    match self {
+      Token::Open(s)
+      | Token::Close(s)
+      | Token::Toggle(s)
+      | Token::Mark(s)
+      | Token::Entity(s)
+      | Token::Escaped(s) => s.len(),
+      Token::Char(c) => c.len_utf8(),
+    }
+  }
+}
+
+fn next_html_token(html: &str) -> Option<Token<'_>> {
+  let c = html.chars().next()?;
+  let token = match c {
+    '<' => html.find('>').map(|end| {
+      let tag = &html[..=end];
+      if tag.starts_with("</") {
+        Token::Close(tag)
+      } else {
+        Token::Open(tag)
+      }
+    }),
+    '&' => html
+      .find(';')
+      .filter(|end| *end <= 10 && !html[1..*end].contains(char::is_whitespace))
+      .map(|end| Token::Entity(&html[..=end])),
+    _ => None,
+  };
+  Some(token.unwrap_or(Token::Char(c)))
+}
+
+/// Byte index of the first `)` in `text` that isn't escaped.
+fn unescaped_paren(text: &str) -> Option<usize> {
+  let mut chars = text.char_indices();
+  while let Some((index, c)) = chars.next() {
+    match c {
+      '\\' => {
+        chars.next();
+      },
+      ')' => return Some(index),
+      _ => {},
+    }
+  }
+  None
+}
+
+fn next_markdown_v2_token(text: &str) -> Option<Token<'_>> {
+  let mut chars = text.chars();
+  let c = chars.next()?;
+  let token = match c {
+    '\\' => chars
+      .next()
+      .map(|escaped| Token::Escaped(&text[..1 + escaped.len_utf8()])),
+    '[' => Some(Token::Open(&text[..1])),
+    ']' => Some(Token::Close(
+      match text[1..].starts_with('(').then(|| unescaped_paren(text)) {
+        Some(Some(end)) => &text[..=end],
+        _ => &text[..1],
+      },
+    )),
+    '>' => Some(Token::Mark(&text[..1])),
+    '_' | '|' if text[1..].starts_with(c) => Some(Token::Toggle(&text[..2])),
+    '_' | '*' | '~' | '`' => Some(Token::Toggle(&text[..1])),
+    _ => None,
+  };
+  Some(token.unwrap_or(Token::Char(c)))
+}
+
+/// Length of `text` as telegram counts it, in UTF-16 units of the text without markup.
+fn markup_len(text: &str, next_token: fn(&str) -> Option<Token<'_>>) -> usize {
+  let mut len = 0;
+  let mut rest = text;
+  while let Some(token) = next_token(rest) {
+    len += token.width();
+    rest = &rest[token.len()..];
+  }
+  len
+}
+
+/// Length of `html` as telegram counts it, in UTF-16 units of the text without tags.
+pub(crate) fn visible_len(html: &str) -> usize {
+  markup_len(html, next_html_token)
+}
+
+/// Length of `text` in MarkdownV2 as telegram counts it.
+pub(crate) fn markdown_v2_len(text: &str) -> usize {
+  markup_len(text, next_markdown_v2_token)
+}
+
+/// Where to end the first part of `text` that fits in `limit`, outside of any element.
+fn find_cut(text: &str, limit: usize, next_token: fn(&str) -> Option<Token<'_>>) -> usize {
+  let (mut newline, mut space, mut any) = (None, None, None);
+  let mut len = 0;
+  let mut depth = 0usize;
+  let mut toggled = Vec::new();
+  let mut pos = 0;
+  let mut prev = None;
+  while let Some(token) = next_token(&text[pos..]) {
+    if depth + toggled.len() == 0 && len > 0 {
+      any = Some(pos);
+      match prev {
+        Some('\n') => newline = Some(pos),
+        Some(' ') => space = Some(pos),
+        _ => {},
+      }
+      if len + token.width() > limit {
+        return newline.or(space).unwrap_or(pos);
+      }
+    }
+    len += token.width();
+    prev = match token {
+      Token::Open(_) => {
+        depth += 1;
+        None
+      },
+      Token::Close(_) => {
+        depth = depth.saturating_sub(1);
+        None
+      },
+      Token::Toggle(mark) => {
+        match toggled.iter().position(|open| *open == mark) {
+          Some(index) => {
+            toggled.remove(index);
+          },
+          None => toggled.push(mark),
+        }
+        None
+      },
+      Token::Mark(_) | Token::Entity(_) | Token::Escaped(_) => None,
+      Token::Char(c) => Some(c),
+    };
+    pos += token.len();
+    // Too long already, but an element can't be split, so cut as soon as it closes.
+    if len > limit && depth + toggled.len() == 0 {
+      return any.map_or(pos, |any| newline.or(space).unwrap_or(any));
+    }
+  }
+  text.len()
+}
+
+fn split(text: &str, limit: usize, next_token: fn(&str) -> Option<Token<'_>>) -> Vec<String> {
+  let mut parts = Vec::new();
+  let mut rest = text;
+  while markup_len(rest, next_token) > limit {
+    let cut = find_cut(rest, limit, next_token);
+    let part = rest[..cut].trim_end();
+    if !part.is_empty() {
+      parts.push(part.to_string());
+    }
+    rest = rest[cut..].trim_start();
+  }
+  if !rest.is_empty() || parts.is_empty() {
+    parts.push(rest.to_string());
+  }
+  parts
+}
+
+/// Splits `html` into parts of at most `limit` characters, preferring line breaks, then spaces.
+///
+/// Elements such as links are never split, so a part holding a longer one exceeds `limit`.
+pub(crate) fn split_html(html: &str, limit: usize) -> Vec<String> {
+  split(html, limit, next_html_token)
+}
+
+/// Splits MarkdownV2 `text` like [`split_html`].
+pub(crate) fn split_markdown_v2(text: &str, limit: usize) -> Vec<String> {
+  split(text, limit, next_markdown_v2_token)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn render_placeholders() {
+    assert_eq!(
+      "由 @alice 发送：{unknown} {text}",
+      render(
+        "由 {user} 发送：{unknown} {text}",
+        &[("user", "@alice"), ("text", "{text}")]
+      )
+    );
+    assert_eq!("{user", render("{user", &[("user", "@alice")]));
+  }
+
+  #[test]
+  fn count_visible_len() {
+    assert_eq!(3, visible_len("<b>a&amp;b</b>"));
+    assert_eq!(2, visible_len("😀"));
+    assert_eq!(5, visible_len("a & b"));
+  }
+
+  #[test]
+  fn split_long_html() {
+    assert_eq!(vec!["short"], split_html("short", 10));
+    assert_eq!(vec!["aaaa", "bbbb"], split_html("aaaa\nbbbb", 6));
+    assert_eq!(vec!["one two", "three"], split_html("one two three", 8));
+    assert_eq!(
+      vec![
+        "head",
+        "<a href=\"https://b23.tv/\">hello world</a>",
+        "tail"
+      ],
+      split_html("head <a href=\"https://b23.tv/\">hello world</a> tail", 8)
+    );
+    assert_eq!(
+      vec!["&amp;&amp;", "&amp;"],
+      split_html("&amp;&amp;&amp;", 2)
+    );
+  }
+
+  #[test]
+  fn split_long_markdown_v2() {
+    assert_eq!(3, markdown_v2_len("*a\\.b*"));
+    assert_eq!(
+      vec!["head", "[hello world](https://b23.tv/\\))", "_tail end_"],
+      split_markdown_v2("head [hello world](https://b23.tv/\\)) _tail end_", 8)
+    );
+  }
+}
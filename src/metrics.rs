@@ -0,0 +1,63 @@
+use std::net::SocketAddr;
+
+use ::metrics::{counter, Counter};
+use anyhow::{Context, Result};
+use axum::{routing::get, Router};
+use fuckburl_bot::replacer::{Platform, ReplaceOutcome, REDIRECT_LATENCY_METRIC};
+use log::info;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tokio::net::TcpListener;
+
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Handles of the counters updated while processing updates, no-ops unless [`install`] ran first.
+#[derive(Debug)]
+pub(crate) struct Handles {
+  pub(crate) updates: Counter,
+  pub(crate) send_failures: Counter,
+  pub(crate) delete_failures: Counter,
+}
+
+impl Handles {
+  pub(crate) fn new() -> Self {
+    Self {
+      updates: counter!("fuckburl_updates_total"),
+      send_failures: counter!("fuckburl_send_failures_total"),
+      delete_failures: counter!("fuckburl_delete_failures_total"),
+    }
+  }
+
+  pub(crate) fn record(&self, outcome: &ReplaceOutcome) {
+    for replacement in &outcome.replacements {
+      replacements(replacement.platform).increment(1);
+    }
+  }
+}
+
+fn replacements(platform: Platform) -> Counter {
+  counter!("fuckburl_replacements_total", "platform" => platform.to_string())
+}
+
+/// Installs the global recorder, must run before any [`Handles`] is created.
+pub(crate) fn install() -> Result<PrometheusHandle> {
+  PrometheusBuilder::new()
+    .set_buckets_for_metric(
+      Matcher::Full(REDIRECT_LATENCY_METRIC.to_string()),
+      LATENCY_BUCKETS,
+    )
+    .context("Invalid histogram buckets")?
+    .install_recorder()
+    .context("Failed to install metrics recorder")
+}
+
+/// Serves the metrics in Prometheus format on `/metrics`.
+pub(crate) async fn serve(listen_addr: SocketAddr, handle: PrometheusHandle) -> Result<()> {
+  let listener = TcpListener::bind(listen_addr)
+    .await
+    .with_context(|| format!("Failed to listen on {listen_addr}"))?;
+  info!("Serving metrics on {listen_addr}");
+  let app = Router::new().route("/metrics", get(move || async move { handle.render() }));
+  axum::serve(listener, app)
+    .await
+    .context("Metrics server exited unexpectedly")
+}
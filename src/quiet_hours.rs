@@ -0,0 +1,68 @@
+use chrono::NaiveTime;
+use serde::{de::Error, Deserialize, Deserializer};
+
+/// A daily window in local time during which messages are left alone, e.g. `22:00`–`06:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub(crate) struct QuietHours {
+  #[serde(deserialize_with = "hh_mm")]
+  start: NaiveTime,
+  #[serde(deserialize_with = "hh_mm")]
+  end: NaiveTime,
+}
+
+impl QuietHours {
+  /// Whether `time` falls in the window, which wraps around midnight when `end` is before `start`.
+  pub(crate) fn contains(&self, time: NaiveTime) -> bool {
+    if self.start <= self.end {
+      self.start <= time && time < self.end
+    } else {
+      time >= self.start || time < self.end
+    }
+  }
+}
+
+fn hh_mm<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let str = String::deserialize(deserializer)?;
+  NaiveTime::parse_from_str(&str, "%H:%M")
+    .map_err(|err| D::Error::custom(format!("invalid time \"{str}\", expected HH:MM: {err}")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hours(start: &str, end: &str) -> QuietHours {
+    toml::from_str(&format!("start = \"{start}\"\nend = \"{end}\"")).unwrap()
+  }
+
+  fn at(time: &str) -> NaiveTime {
+    NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+  }
+
+  #[test]
+  fn within_same_day() {
+    let quiet = hours("01:30", "07:00");
+    assert!(quiet.contains(at("01:30")));
+    assert!(quiet.contains(at("06:59")));
+    assert!(!quiet.contains(at("07:00")));
+    assert!(!quiet.contains(at("23:00")));
+  }
+
+  #[test]
+  fn wraps_around_midnight() {
+    let quiet = hours("22:00", "06:00");
+    assert!(quiet.contains(at("22:00")));
+    assert!(quiet.contains(at("00:00")));
+    assert!(quiet.contains(at("05:59")));
+    assert!(!quiet.contains(at("06:00")));
+    assert!(!quiet.contains(at("12:00")));
+  }
+
+  #[test]
+  fn rejects_malformed_time() {
+    assert!(toml::from_str::<QuietHours>("start = \"25:00\"\nend = \"06:00\"").is_err());
+  }
+}
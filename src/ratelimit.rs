@@ -0,0 +1,77 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Serializes outbound messages per chat, spacing them at least `interval` apart.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+  interval: Mutex<Option<Duration>>,
+  chats: Mutex<HashMap<i64, Arc<tokio::sync::Mutex<Option<Instant>>>>>,
+}
+
+/// `0` disables limiting.
+fn interval(max_msgs_per_sec: u32) -> Option<Duration> {
+  (max_msgs_per_sec > 0).then(|| Duration::from_secs(1) / max_msgs_per_sec)
+}
+
+impl RateLimiter {
+  /// `0` disables limiting.
+  pub(crate) fn new(max_msgs_per_sec: u32) -> Self {
+    Self {
+      interval: Mutex::new(interval(max_msgs_per_sec)),
+      chats: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Changes the rate for messages sent from now on, e.g. on a config reload.
+  pub(crate) fn set_max_msgs_per_sec(&self, max_msgs_per_sec: u32) {
+    *self.interval.lock().unwrap() = interval(max_msgs_per_sec);
+  }
+
+  /// Waits until a message may be sent to `chat_id`.
+  pub(crate) async fn acquire(&self, chat_id: i64) {
+    let Some(interval) = *self.interval.lock().unwrap() else {
+      return;
+    };
+    let slot = Arc::clone(self.chats.lock().unwrap().entry(chat_id).or_default());
+    let mut last_sent = slot.lock().await;
+    if let Some(last_sent) = *last_sent {
+      tokio::time::sleep_until(last_sent + interval).await;
+    }
+    *last_sent = Some(Instant::now());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn spaces_messages_per_chat() {
+    let limiter = RateLimiter::new(10);
+    let start = Instant::now();
+    limiter.acquire(1).await;
+    limiter.acquire(2).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+    limiter.acquire(1).await;
+    assert!(start.elapsed() >= Duration::from_millis(100));
+  }
+
+  #[tokio::test]
+  async fn disabled_when_zero() {
+    let limiter = RateLimiter::new(0);
+    let start = Instant::now();
+    limiter.acquire(1).await;
+    limiter.acquire(1).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    limiter.set_max_msgs_per_sec(10);
+    limiter.acquire(1).await;
+    limiter.acquire(1).await;
+    assert!(start.elapsed() >= Duration::from_millis(100));
+  }
+}
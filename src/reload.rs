@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use fuckburl_bot::replacer;
+use log::{error, info, warn};
+use tokio::sync::Semaphore;
+
+use crate::{
+  allowed_updates, client_options, read_config, replace_options, validate_config, Config, Shared,
+  Webhook,
+};
+
+/// Re-reads the config file on every SIGHUP, swapping it in for updates processed afterwards.
+///
+/// Settings listed by [`restart_only_changes`] still need a restart.
+#[cfg(unix)]
+pub(crate) async fn watch(shared: Arc<Shared>) {
+  use tokio::signal::unix::{signal, SignalKind};
+
+  let mut hangup = match signal(SignalKind::hangup()) {
+    Ok(hangup) => hangup,
+    Err(err) => {
+      error!("Failed to listen for SIGHUP, config reload is disabled: {err}");
+      return;
+    },
+  };
+  while hangup.recv().await.is_some() {
+    if let Err(err) = reload(&shared) {
+      error!("Failed to reload config, keeping the current one: {err:?}");
+    }
+  }
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn watch(_shared: Arc<Shared>) {}
+
+/// Reads [`Shared::config_path`] again, keeping the current config if it fails to parse.
+///
+/// Returns the changed settings that only apply after a restart.
+pub(crate) fn reload(shared: &Shared) -> Result<Vec<&'static str>> {
+  info!(
+    "Reloading config from {}...",
+    shared.config_path.to_string_lossy()
+  );
+  let mut config = read_config(&shared.config_path)?;
+  validate_config(&config)?;
+  let current = shared.config();
+  let restart_only = restart_only_changes(&current, &config);
+  if !restart_only.is_empty() {
+    warn!(
+      "Changes to {} only apply after a restart",
+      restart_only.join(", ")
+    );
+  }
+  config.state_file.clone_from(&current.state_file);
+  config.chats_file.clone_from(&current.chats_file);
+
+  // Rebuilding drops the short links already resolved, so only when needed.
+  let client = client_options(&config);
+  if client != client_options(&current) {
+    replacer::set_redirect_client(&client)?;
+  }
+  replacer::set_replace_options(replace_options(&config));
+  shared.limiter.set_max_msgs_per_sec(config.max_msgs_per_sec);
+  if config.max_concurrent_updates != current.max_concurrent_updates {
+    // Updates already waiting keep the old bound, the new one applies to those after them.
+    shared.update_permits.store(Arc::new(Semaphore::new(
+      config.max_concurrent_updates.max(1),
+    )));
+  }
+  shared.config.store(Arc::new(config));
+  info!("Config reloaded");
+  Ok(restart_only)
+}
+
+/// Settings differing between `current` and `config` that are only read at startup.
+fn restart_only_changes(current: &Config, config: &Config) -> Vec<&'static str> {
+  let webhook_updates =
+    config.webhook.is_some() && allowed_updates(config) != allowed_updates(current);
+  [
+    (
+      "telegram-token",
+      config.telegram_token != current.telegram_token,
+    ),
+    ("api-base-url", config.api_base_url != current.api_base_url),
+    // Only for the telegram client, the one resolving short links is rebuilt above.
+    ("proxy", config.proxy != current.proxy),
+    (
+      "webhook",
+      Webhook::needs_restart(config.webhook.as_ref(), current.webhook.as_ref()),
+    ),
+    (
+      "handle-edits and enable-channels with a webhook",
+      webhook_updates,
+    ),
+    ("metrics", config.metrics != current.metrics),
+    ("health", config.health != current.health),
+    ("state-file", config.state_file != current.state_file),
+    ("chats-file", config.chats_file != current.chats_file),
+  ]
+  .into_iter()
+  .filter_map(|(name, changed)| changed.then_some(name))
+  .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parse_config;
+
+  #[test]
+  fn list_restart_only_changes() {
+    let current = parse_config(
+      "telegram-token = \"1:a\"\nmax-msgs-per-sec = 1\nproxy = \"http://127.0.0.1:1080\"",
+      false,
+    )
+    .unwrap();
+    let config = parse_config(
+      "telegram-token = \"1:a\"\nmax-msgs-per-sec = 5\nenabled-chats = [\"chat\"]\nredirect-proxy = \"socks5://127.0.0.1:1080\"",
+      false,
+    )
+    .unwrap();
+    assert_eq!(vec!["proxy"], restart_only_changes(&current, &config));
+    assert!(restart_only_changes(&current, &current).is_empty());
+  }
+}
@@ -1,30 +1,273 @@
 use std::{
   borrow::{Borrow, Cow},
+  collections::{HashMap, HashSet},
+  fmt::{self, Display, Formatter},
+  ops::Range,
   str::FromStr,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
-use fancy_regex::Regex;
-use frankenstein::reqwest::{redirect, Client, ClientBuilder, Url};
-use log::error;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use fancy_regex::{Captures, Regex};
+use frankenstein::reqwest::{self, redirect, Client, ClientBuilder, Url};
+use futures::future::join_all;
+use log::{error, warn};
 use once_cell::sync::Lazy;
+use serde::Deserialize;
 
-const UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36";
+/// User agent of the client resolving short links, unless configured otherwise.
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36";
 
-static CLIENT_REDIRECT_ONCE: Lazy<Client> = Lazy::new(|| {
-  let once_redirect = redirect::Policy::custom(|attempt| {
-    if attempt.previous().len() > 1 {
+/// Histogram of how long resolving a short link over HTTP takes, in seconds.
+pub const REDIRECT_LATENCY_METRIC: &str = "fuckburl_redirect_resolve_duration_seconds";
+
+/// Resolved short links kept at most, the oldest is evicted first.
+const REDIRECT_CACHE_CAPACITY: usize = 1024;
+
+static REDIRECT_RESOLVER: Lazy<ArcSwap<CachedResolver<ReqwestResolver>>> = Lazy::new(|| {
+  ArcSwap::from_pointee(
+    build_redirect_resolver(&ClientOptions::default()).expect("Unable to build reqwest client"),
+  )
+});
+static REPLACE_OPTIONS: Lazy<ArcSwap<ReplaceOptions>> = Lazy::new(ArcSwap::default);
+
+/// Settings of the client resolving short links.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientOptions {
+  pub user_agent: String,
+  pub timeout: Duration,
+  /// How long a resolved short link is reused across messages, zero disables caching
+  pub cache_ttl: Duration,
+  /// HTTP(S) or SOCKS5 proxy short links are resolved through
+  pub proxy: Option<String>,
+  /// Redirects followed for short links of these hosts, instead of one
+  pub redirect_hops: HashMap<String, usize>,
+}
+
+impl Default for ClientOptions {
+  fn default() -> Self {
+    Self {
+      user_agent: DEFAULT_USER_AGENT.to_string(),
+      timeout: Duration::from_millis(5000),
+      cache_ttl: Duration::from_secs(3600),
+      proxy: None,
+      redirect_hops: HashMap::new(),
+    }
+  }
+}
+
+fn build_redirect_client(options: &ClientOptions) -> Result<Client> {
+  let redirect_hops = options.redirect_hops.clone();
+  let policy = redirect::Policy::custom(move |attempt| {
+    // The first of the previous urls is the short link itself.
+    let hops = attempt
+      .previous()
+      .first()
+      .and_then(Url::host_str)
+      .and_then(|host| redirect_hops.get(host))
+      .copied()
+      .unwrap_or(1);
+    if attempt.previous().len() > hops {
       attempt.stop()
     } else {
       attempt.follow()
     }
   });
-  ClientBuilder::new()
-    .user_agent(UA)
-    .redirect(once_redirect)
-    .build()
-    .expect("Unable to build reqwest client")
-});
+  let mut builder = ClientBuilder::new()
+    .user_agent(&options.user_agent)
+    .redirect(policy)
+    .timeout(options.timeout);
+  if let Some(proxy) = &options.proxy {
+    let proxy = reqwest::Proxy::all(proxy.as_str())
+      .with_context(|| format!("Failed to set \"{proxy}\" as redirect proxy"))?;
+    builder = builder.proxy(proxy);
+  }
+  builder.build().context("Unable to build reqwest client")
+}
+
+fn build_redirect_resolver(options: &ClientOptions) -> Result<CachedResolver<ReqwestResolver>> {
+  Ok(CachedResolver::new(
+    ReqwestResolver {
+      client: build_redirect_client(options)?,
+    },
+    options.cache_ttl,
+    REDIRECT_CACHE_CAPACITY,
+  ))
+}
+
+/// Builds the client resolving short links, swapping out the previous one along with the links it
+/// cached, e.g. on a config reload. The previous client is kept if building fails.
+pub fn set_redirect_client(options: &ClientOptions) -> Result<()> {
+  REDIRECT_RESOLVER.store(Arc::new(build_redirect_resolver(options)?));
+  Ok(())
+}
+
+fn redirect_resolver() -> Arc<CachedResolver<ReqwestResolver>> {
+  REDIRECT_RESOLVER.load_full()
+}
+
+/// Settings of how links are cleaned.
+#[derive(Debug, Clone)]
+pub struct ReplaceOptions {
+  /// Keep `xsec_token` on xiaohongshu links, some notes don't load without it
+  pub xhs_keep_token: bool,
+  /// Query keys kept per platform, overriding the built-in lists
+  pub keep_params: HashMap<Platform, KeepParams>,
+  /// Passes over short links redirecting to further short links
+  pub max_resolve_iterations: usize,
+  /// Applied after the built-in replacers
+  pub custom_rules: Vec<CustomRule>,
+  /// Stripped from links of any site, in addition to [`COMMON_TRACKERS`]
+  pub extra_trackers: Vec<String>,
+  /// Links to these domains and their subdomains are never touched
+  pub domain_blocklist: Vec<String>,
+  /// Trackers are also stripped from the fragment of links to these domains and their subdomains
+  pub fragment_tracker_domains: Vec<String>,
+  /// Platforms switched on or off in every chat, those missing are on
+  pub replacers: HashMap<Platform, bool>,
+}
+
+impl Default for ReplaceOptions {
+  fn default() -> Self {
+    Self {
+      xhs_keep_token: false,
+      keep_params: HashMap::new(),
+      max_resolve_iterations: 3,
+      custom_rules: Vec::new(),
+      extra_trackers: Vec::new(),
+      domain_blocklist: Vec::new(),
+      fragment_tracker_domains: Vec::new(),
+      replacers: HashMap::new(),
+    }
+  }
+}
+
+/// Query keys kept on links of a platform, or for bilibili per section of its links.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum KeepParams {
+  Keys(Vec<String>),
+  /// By section, e.g. `{ video = ["p", "t"], live = [] }`
+  Sections(HashMap<String, Vec<String>>),
+}
+
+impl ReplaceOptions {
+  /// Query keys kept on links of `platform`, `default` unless configured.
+  fn kept_keys<'a>(&'a self, platform: Platform, default: &'a [&'a str]) -> Cow<'a, [&'a str]> {
+    match self.keep_params.get(&platform) {
+      Some(KeepParams::Keys(keys)) => Cow::Owned(keys.iter().map(String::as_str).collect()),
+      _ => Cow::Borrowed(default),
+    }
+  }
+
+  /// Query keys kept on links in `section` of `platform`, `default` unless configured for it.
+  fn kept_section_keys<'a>(
+    &'a self,
+    platform: Platform,
+    section: &str,
+    default: &'a [&'a str],
+  ) -> Cow<'a, [&'a str]> {
+    match self.keep_params.get(&platform) {
+      Some(KeepParams::Sections(sections)) => match sections.get(section) {
+        Some(keys) => Cow::Owned(keys.iter().map(String::as_str).collect()),
+        None => Cow::Borrowed(default),
+      },
+      _ => Cow::Borrowed(default),
+    }
+  }
+
+  /// Whether the replacers of `platform` run at all, unless a chat disables them.
+  pub fn is_enabled(&self, platform: Platform) -> bool {
+    self.replacers.get(&platform).copied().unwrap_or(true)
+  }
+
+  /// The platforms `disabled` in a chat along with those switched off everywhere.
+  fn with_disabled(&self, disabled: &HashSet<Platform>) -> HashSet<Platform> {
+    let mut all = disabled.clone();
+    all.extend(
+      self
+        .replacers
+        .keys()
+        .copied()
+        .filter(|platform| !self.is_enabled(*platform)),
+    );
+    all
+  }
+}
+
+/// Query keys that only track where a link was shared, stripped from links of any site.
+pub const COMMON_TRACKERS: &[&str] = &[
+  "utm_source",
+  "utm_medium",
+  "utm_campaign",
+  "utm_term",
+  "utm_content",
+  "gclid",
+  "fbclid",
+  "mc_eid",
+];
+
+/// A user-defined rewrite of links matching `pattern`, the regex is checked when deserializing.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "CustomRuleRepr")]
+pub struct CustomRule {
+  regex: Regex,
+  /// May refer to groups of the pattern, as `$1` or `$name`
+  replacement: String,
+  /// Query keys kept on the rewritten link, all of them unless set
+  keep_params: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+struct CustomRuleRepr {
+  pattern: String,
+  replacement: String,
+  keep_params: Option<Vec<String>>,
+}
+
+impl TryFrom<CustomRuleRepr> for CustomRule {
+  type Error = String;
+
+  fn try_from(repr: CustomRuleRepr) -> std::result::Result<Self, Self::Error> {
+    let regex = Regex::new(&repr.pattern)
+      .map_err(|err| format!("invalid pattern \"{}\": {err}", repr.pattern))?;
+    Ok(Self {
+      regex,
+      replacement: repr.replacement,
+      keep_params: repr.keep_params,
+    })
+  }
+}
+
+impl CustomRule {
+  fn replace(&self, url: &str) -> String {
+    let replaced = self.regex.replace(url, self.replacement.as_str());
+    let Some(keep_params) = &self.keep_params else {
+      return replaced.into();
+    };
+    match Url::parse(&replaced) {
+      Ok(mut url) => {
+        url.keep_pairs_only_in(keep_params.iter().map(String::as_str).collect());
+        url.into()
+      },
+      Err(_) => replaced.into(),
+    }
+  }
+}
+
+/// Sets how links are cleaned, swapping out the previous options, e.g. on a config reload.
+/// Messages already being cleaned may see either.
+pub fn set_replace_options(options: ReplaceOptions) {
+  REPLACE_OPTIONS.store(Arc::new(options));
+}
+
+fn replace_options() -> Arc<ReplaceOptions> {
+  REPLACE_OPTIONS.load_full()
+}
 
 static BSHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
   Regex::new(
@@ -38,7 +281,7 @@ static BVIDEO_REGEX: Lazy<Regex> = Lazy::new(|| {
 });
 
 static YOUTUBE_REGEX: Lazy<Regex> = Lazy::new(|| {
-  Regex::new(r"(https?://|(?<![a-zA-Z])|^)(www\.)?(youtube\.com|youtu\.be)/(watch|[a-zA-Z_]+)\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)((www|m|music)\.)?(youtube\.com|youtu\.be)/(watch|shorts/[0-9a-zA-Z_-]+|[0-9a-zA-Z_-]+)\??(?:&?[^=&]*=[^=&]*)*").unwrap()
 });
 static BARTICLE_REGEX: Lazy<Regex> = Lazy::new(|| {
   Regex::new(r"(https?://|(?<![a-zA-Z])|^)(www\.)?bilibili\.com/read/mobile/(?P<cvid>[0-9]+)\??(?:&?[^=&]*=[^=&]*)*").unwrap()
@@ -46,6 +289,24 @@ static BARTICLE_REGEX: Lazy<Regex> = Lazy::new(|| {
 static BOPUS_REGEX: Lazy<Regex> = Lazy::new(|| {
   Regex::new(r"(https?://|(?<![a-zA-Z])|^)((www|m)\.)?bilibili\.com/opus/(?P<opus_id>[0-9]+)\??(?:&?[^=&]*=[^=&]*)*").unwrap()
 });
+static BBANGUMI_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)((www|m)\.)?bilibili\.com/bangumi/play/(?P<id>(ep|ss)[0-9]+)/?\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static BCHEESE_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)((www|m)\.)?bilibili\.com/cheese/play/(?P<id>(ep|ss)[0-9]+)/?\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static BSPACE_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)space\.bilibili\.com/(?P<path>[0-9]+(/[a-zA-Z]+)*)/?\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static BDYNAMIC_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)(t\.bilibili\.com|m\.bilibili\.com/dynamic)/(?P<dynamic_id>[0-9]+)/?\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static BFESTIVAL_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)((www|m)\.)?bilibili\.com/festival/[0-9a-zA-Z_-]+/?\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static BLIVE_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)live\.bilibili\.com/(h5/)?(?P<room_id>[0-9]+)/?\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
 static AMAZON_REGEX: Lazy<Regex> = Lazy::new(|| {
   Regex::new(
   r"(?P<domain>(https?://|(?<![a-zA-Z])|^)(www\.)?amazon\.(com|co(\.[a-zA-Z]+)?)/)[a-zA-Z0-9%-]+/(?P<path>dp/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*"
@@ -83,365 +344,3410 @@ static JD_REGEX: Lazy<Regex> = Lazy::new(|| {
 static TWITTER_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
   Regex::new(r"((https?://|(?<![a-zA-Z])|^)t\.co/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*").unwrap()
 });
+static REDDIT_SHARE_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"((https?://|(?<![a-zA-Z])|^)(www\.|old\.)?reddit\.com/r/[0-9a-zA-Z_]+/s/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*"
+).unwrap()
+});
+static REDDIT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(?P<url>(https?://|(?<![a-zA-Z])|^)(www\.|old\.)?reddit\.com/r/[0-9a-zA-Z_]+/comments/[0-9a-zA-Z]+(/[0-9a-zA-Z_%-]*)?/?)\??(?:&?[^=&]*=[^=&]*)*"
+).unwrap()
+});
+static INSTAGRAM_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)(www\.)?(instagram\.com|instagr\.am)(?P<path>/(reel|p)/[0-9a-zA-Z_-]+/?)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static ALIEXPRESS_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)([a-z]{2,3}\.)?aliexpress\.(com|us|ru)(?P<path>/item/[0-9]+\.html)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static AMAZON_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+    r"((https?://|(?<![a-zA-Z])|^)(amzn\.(to|eu|asia)|a\.co/d)/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*",
+  )
+  .unwrap()
+});
+static ALIEXPRESS_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+    r"((https?://|(?<![a-zA-Z])|^)a\.aliexpress\.com/_[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*",
+  )
+  .unwrap()
+});
+static TAOBAO_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)(item\.taobao\.com|detail\.(m\.)?tmall\.com)/item\.htm\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static TAOBAO_SHARE_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)m\.tb\.cn/h\.[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*")
+    .unwrap()
+});
+static XHSLINK_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+    r"((https?://|(?<![a-zA-Z])|^)xhslink\.com/([a-zA-Z]/)?[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*",
+  )
+  .unwrap()
+});
+static SPOTIFY_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)open\.spotify\.com(?P<path>/(track|album|playlist|episode|show)/[0-9a-zA-Z]+)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static FACEBOOK_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)((www|m|web)\.)?(facebook|fb)\.com/[0-9a-zA-Z_./-]+\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static FBWATCH_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)fb\.watch/[0-9a-zA-Z_-]+/?)\??(?:&?[^=&]*=[^=&]*)*")
+    .unwrap()
+});
+static STEAM_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)store\.steampowered\.com(?P<path>/(app|sub|bundle)/[0-9]+/?([^/?#\s]+/?)?)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static STEAM_MARKET_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)steamcommunity\.com(?P<path>/market/listings/[0-9]+/[^/?#\s]+)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static EPIC_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)store\.epicgames\.com(/[a-z]{2}(-[A-Z]{2})?)?(?P<path>/p/[0-9a-zA-Z_-]+)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static GOG_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)(www\.)?gog\.com(/[a-z]{2})?(?P<path>/game/[0-9a-zA-Z_]+)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static DISCORD_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)(?P<host>((www|ptb|canary)\.)?discord(app)?\.(com|gg))(?P<path>/[^\s?#]*)?\?(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+// Without a scheme, a dot before `github.com` means another host like `gist.github.com`.
+static GITHUB_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z.])|^)(www\.)?github\.com(/[^\s?#]*)?\?(?:&?[^=&]*=[^=&]*)*")
+    .unwrap()
+});
+static URL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r#"https?://[^\s<>"]+"#).unwrap());
+static TWITCH_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)(?P<host>(www\.|m\.|clips\.)?twitch\.tv)(?P<path>/[^\s?#]*)?(?P<query>\?(?:&?[^=&]*=[^=&]*)*)?").unwrap()
+});
+static PIXIV_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)(www\.)?pixiv\.net(/[a-z]{2}(-[a-z]{2})?)?/artworks/(?P<id>[0-9]+)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
 static TIKTOK_SHARE_REGEX: Lazy<Regex> = Lazy::new(|| {
   Regex::new(
   r"((https?://|(?<![a-zA-Z])|^)(vm|vt|www)\.tiktok\.com/(t/)?[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*"
 ).unwrap()
 });
+static DOUYIN_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)v\.douyin\.com/[0-9a-zA-Z_-]+/?)\??(?:&?[^=&]*=[^=&]*)*")
+    .unwrap()
+});
+static NICO_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+  r"(https?://|(?<![a-zA-Z])|^)((www|sp)\.)?nicovideo\.jp/watch/(?P<id>[a-z]{2}[0-9]+|[0-9]+)\??(?:&?[^=&]*=[^=&]*)*"
+)
+.unwrap()
+});
+static NICO_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)nico\.ms/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*")
+    .unwrap()
+});
+static GMAPS_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)(?P<url>(www\.)?google\.[a-z]{2,3}(\.[a-z]{2})?/maps(/[^\s?#]*)?|maps\.google\.[a-z]{2,3}(\.[a-z]{2})?(/[^\s?#]*)?)(?P<query>\?(?:&?[^=&]*=[^=&]*)*)?").unwrap()
+});
+static GMAPS_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+    r"((https?://|(?<![a-zA-Z])|^)(maps\.app\.goo\.gl|goo\.gl/maps)/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*",
+  )
+  .unwrap()
+});
+static LINEE_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)lin\.ee/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static KAKAO_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)kko\.to/[0-9a-zA-Z_-]+/?)\??(?:&?[^=&]*=[^=&]*)*")
+    .unwrap()
+});
+static WEIBO_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)((www|m)\.)?weibo\.(com|cn)/(?P<path>([0-9]+|u|status|detail)(/[0-9a-zA-Z]+)*)/?\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static TCN_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)t\.cn/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static URLCN_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)url\.cn/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static QQDOCS_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+    r"(https?://|(?<![a-zA-Z])|^)docs\.qq\.com/[a-z]+/[0-9a-zA-Z_-]+/?\??(?:&?[^=&]*=[^=&]*)*",
+  )
+  .unwrap()
+});
+static QQ_MIDDLE_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)c\.pc\.qq\.com/[0-9a-zA-Z_]+\.html\?(?:&?[^=&]*=[^=&]*)*")
+    .unwrap()
+});
+static NAVER_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)(m\.)?(?P<site>blog|cafe)\.naver\.com/(?P<path>[0-9a-zA-Z_-]+(/[0-9]+)?)(?![0-9a-zA-Z_.-])/?\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
+static NAVERME_SHORT_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"((https?://|(?<![a-zA-Z])|^)naver\.me/[0-9a-zA-Z]+/?)\??(?:&?[^=&]*=[^=&]*)*")
+    .unwrap()
+});
+static APPLE_MUSIC_REGEX: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(https?://|(?<![a-zA-Z])|^)(music|podcasts)\.apple\.com/[a-z]{2}/(album|song|playlist|artist|music-video|podcast)/[^\s?#]+\??(?:&?[^=&]*=[^=&]*)*").unwrap()
+});
 
-pub async fn replace_all(text: &str) -> Result<String> {
-  let mut new = text.to_string();
-  new = replace_bshort(&new)
-    .await
-    .context("Failed to replace short url")?;
-  new = replace_twitter_short(&new)
-    .await
-    .context("Failed to replace twitter short url")?;
-  new = replace_tiktok_share(&new)
-    .await
-    .context("Failed to replace tiktok share url")?;
-  replace_youtube(&mut new);
-  replace_btrack(&mut new);
-  new = replace_barticle(&new);
-  new = replace_bopus(&new);
-  new = replace_twitter(&new);
-  new = replace_twitter_x(&new);
-  new = replace_amazon(&new);
-  new = replace_amazon_search(&new);
-  new = replace_weixin(&new);
-  new = replace_jd(&new);
-  Ok(new)
+/// Platforms whose replacers can be switched off, e.g. per chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+  Bilibili,
+  Twitter,
+  Tiktok,
+  Youtube,
+  Amazon,
+  Weixin,
+  Jd,
+  Instagram,
+  Reddit,
+  Aliexpress,
+  Taobao,
+  Spotify,
+  Facebook,
+  Pixiv,
+  Xiaohongshu,
+  Steam,
+  Douyin,
+  Github,
+  Twitch,
+  Niconico,
+  GoogleMaps,
+  Line,
+  Kakao,
+  Epic,
+  Gog,
+  Discord,
+  Weibo,
+  Qq,
+  AppleMusic,
+  Naver,
+  /// Common trackers like `utm_source` on links of any site
+  Generic,
+  /// Rules from the config's `[[custom-rules]]`
+  Custom,
 }
 
-fn replace_twitter(url: &str) -> String {
-  TWITTER_REGEX.replace(url, "https://fixupx.com$path").into()
+/// A link cleaned by [`replace_all`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+  pub platform: Platform,
+  pub original: String,
+  pub replaced: String,
 }
 
-fn replace_twitter_x(url: &str) -> String {
-  TWITTER_X_REGEX
-    .replace(url, "https://fixupx.com$path")
-    .into()
-}
+/// Query keys earning whoever shared a link a commission.
+const AFFILIATE_PARAMS: &[(Platform, &[&str])] = &[
+  (Platform::Amazon, &["tag", "ascsubtag"]),
+  (
+    Platform::Aliexpress,
+    &["aff_fcid", "aff_fsk", "aff_platform", "aff_trace_key"],
+  ),
+];
 
-fn replace_weixin(text: &str) -> String {
-  let mut new_str = text.to_string();
-  for i in WEIXIN_REGEX.find_iter(text) {
-    let i = match i {
-      Ok(i) => i,
-      Err(err) => {
-        error!("Failed to find_iter: {err}");
-        continue;
-      },
-    };
-    let mut url = if let Ok(url) = Url::from_str(i.as_str()) {
-      url
-    } else {
-      continue;
-    };
-    const KEYS: Cow<[&str]> = Cow::Borrowed(&["__biz", "mid", "idx", "sn"]);
-    url.keep_pairs_only_in(KEYS);
-    new_str.replace_range(i.range(), url.to_string().as_str());
+/// The affiliate keys in the query of `replacement`'s original link that the cleaned one lacks.
+fn removed_affiliate_params(replacement: &Replacement) -> Vec<String> {
+  fn query_keys(url: &str) -> Vec<String> {
+    let query = url.split_once('?').map_or("", |(_, query)| query);
+    let query = query.split_once('#').map_or(query, |(query, _)| query);
+    form_urlencoded::parse(query.as_bytes())
+      .map(|(key, _)| key.into_owned())
+      .collect()
   }
-  new_str
-}
 
-fn replace_jd(url: &str) -> String {
-  JD_REGEX.replace_all(url, "$url").into()
+  let Some((_, affiliate)) = AFFILIATE_PARAMS
+    .iter()
+    .find(|(platform, _)| *platform == replacement.platform)
+  else {
+    return Vec::new();
+  };
+  let kept = query_keys(&replacement.replaced);
+  query_keys(&replacement.original)
+    .into_iter()
+    .filter(|key| affiliate.contains(&key.as_str()) && !kept.contains(key))
+    .collect()
 }
 
-fn replace_amazon(url: &str) -> String {
-  AMAZON_REGEX.replace_all(url, "$domain$path").into()
+impl Display for Platform {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    // Same as the names in the config
+    write!(f, "{}", format!("{self:?}").to_lowercase())
+  }
 }
 
-fn replace_amazon_search(url: &str) -> String {
-  AMAZON_SEARCH_REGEX
-    .replace_all(url, "$domain$keyword")
-    .into()
+/// Result of cleaning a piece of text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplaceOutcome {
+  pub text: String,
+  pub changed: bool,
+  /// In the order they were replaced
+  pub replacements: Vec<Replacement>,
+  /// Affiliate query keys removed from the links, e.g. Amazon's `tag`
+  pub affiliate_params: Vec<String>,
 }
 
-fn trim_youtube_link(url: &mut Url) {
-  const KEYS: Cow<[&str]> = Cow::Borrowed(&["v", "list", "index", "t"]);
-  url.keep_pairs_only_in(KEYS);
+pub async fn replace_all(text: &str, disabled: &HashSet<Platform>) -> Result<ReplaceOutcome> {
+  replace_all_with_resolver(text, disabled, &*redirect_resolver()).await
 }
 
-fn replace_youtube(text: &mut String) {
-  let mut replaces = Vec::new();
-  for i in YOUTUBE_REGEX.find_iter(text) {
-    let i = match i {
-      Ok(i) => i,
-      Err(err) => {
-        error!("Failed to find_iter: {err}");
-        continue;
-      },
-    };
-    let Ok(mut url) = Url::from_str(i.as_str()) else {
-      continue;
-    };
-    trim_youtube_link(&mut url);
-    replaces.push((i.range(), url.to_string()));
-  }
-  for (range, str) in replaces {
-    text.replace_range(range, str.as_str());
-  }
+/// Like [`replace_all`], giving up on short links still resolving at `deadline`, which are left as
+/// they are. Site rules always run on the rest.
+pub async fn replace_all_with_deadline(
+  text: &str,
+  disabled: &HashSet<Platform>,
+  deadline: tokio::time::Instant,
+) -> Result<ReplaceOutcome> {
+  replace_all_until(text, disabled, &*redirect_resolver(), Some(deadline)).await
 }
 
-fn trim_bili_link(url: &mut Url) {
-  const KEYS: Cow<[&str]> = Cow::Borrowed(&["p", "t"]);
-  url.keep_pairs_only_in(KEYS);
+/// Like [`replace_all`], resolving short links with `resolver` instead of over HTTP.
+pub async fn replace_all_with_resolver(
+  text: &str,
+  disabled: &HashSet<Platform>,
+  resolver: &dyn RedirectResolver,
+) -> Result<ReplaceOutcome> {
+  replace_all_until(text, disabled, resolver, None).await
 }
 
-fn replace_btrack(text: &mut String) {
-  let mut replaces = Vec::new();
-  for i in BVIDEO_REGEX.find_iter(text) {
-    let i = match i {
-      Ok(i) => i,
-      Err(err) => {
-        error!("Failed to find_iter: {err}");
-        continue;
-      },
-    };
-    let Ok(mut url) = Url::from_str(i.as_str()) else {
-      continue;
-    };
-    trim_bili_link(&mut url);
-    replaces.push((i.range(), url.to_string()));
+async fn replace_all_until(
+  text: &str,
+  disabled: &HashSet<Platform>,
+  resolver: &dyn RedirectResolver,
+  deadline: Option<tokio::time::Instant>,
+) -> Result<ReplaceOutcome> {
+  // Platforms switched off in `[replacers]` are off for every entry point.
+  let disabled = &replace_options().with_disabled(disabled);
+  // Messages often repeat a link, e.g. in a quote and the body.
+  let resolver = &Memoized::new(resolver);
+  let enabled = |platform: Platform| !disabled.contains(&platform);
+  let mut new = text.to_string();
+  let mut replacements = Vec::new();
+  let reps = &mut replacements;
+  // A short link may redirect to another one, e.g. t.co to b23.tv, so resolve again until
+  // nothing changes, bounded in case two of them redirect to each other.
+  let mut seen = HashSet::from([new.clone()]);
+  for _ in 0..replace_options().max_resolve_iterations.max(1) {
+    if deadline.is_some_and(|deadline| deadline <= tokio::time::Instant::now()) {
+      break;
+    }
+    let found = reps.len();
+    resolve_short_links(&mut new, disabled, resolver, deadline, reps).await?;
+    if reps.len() == found || !seen.insert(new.clone()) {
+      break;
+    }
   }
-  for (range, str) in replaces {
-    text.replace_range(range, str.as_str());
+  if enabled(Platform::Youtube) {
+    record(
+      &mut new,
+      Platform::Youtube,
+      &YOUTUBE_REGEX,
+      in_place(replace_youtube),
+      reps,
+    );
   }
-}
-
-async fn replace_bshort(str: &str) -> Result<String> {
-  let mut new_str = str.to_string();
-  let matches: Vec<_> = BSHORT_REGEX.find_iter(str).collect();
-  for x in matches.iter() {
-    let x = match x {
-      Ok(x) => x,
-      Err(err) => {
-        error!("Failed to find_iter: {err}");
-        continue;
-      },
-    };
-    let mut url = get_redirect_url(x.as_str()).await?;
-    trim_bili_link(&mut url);
-    new_str.replace_range(x.range(), url.to_string().as_str());
+  if enabled(Platform::Bilibili) {
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BFESTIVAL_REGEX,
+      replace_bfestival,
+      reps,
+    );
+    // Bangumi pages aren't under /video, so BVIDEO_REGEX never sees them.
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BBANGUMI_REGEX,
+      replace_bbangumi,
+      reps,
+    );
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BCHEESE_REGEX,
+      replace_bcheese,
+      reps,
+    );
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BVIDEO_REGEX,
+      in_place(replace_btrack),
+      reps,
+    );
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BARTICLE_REGEX,
+      replace_barticle,
+      reps,
+    );
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BOPUS_REGEX,
+      replace_bopus,
+      reps,
+    );
+    // Opus links were just rewritten to bare dynamic ones, which this leaves as they are.
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BDYNAMIC_REGEX,
+      replace_bdynamic,
+      reps,
+    );
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BSPACE_REGEX,
+      replace_bspace,
+      reps,
+    );
+    record(
+      &mut new,
+      Platform::Bilibili,
+      &BLIVE_REGEX,
+      replace_blive,
+      reps,
+    );
   }
-  Ok(new_str)
-}
-
-async fn replace_twitter_short(str: &str) -> Result<String> {
-  let mut new_str = str.to_string();
-  let matches: Vec<_> = TWITTER_SHORT_REGEX.find_iter(str).collect();
-  for x in matches.iter() {
-    let x = match x {
-      Ok(x) => x,
+  if enabled(Platform::Twitter) {
+    record(
+      &mut new,
+      Platform::Twitter,
+      &TWITTER_REGEX,
+      replace_twitter,
+      reps,
+    );
+    record(
+      &mut new,
+      Platform::Twitter,
+      &TWITTER_X_REGEX,
+      replace_twitter_x,
+      reps,
+    );
+  }
+  if enabled(Platform::Amazon) {
+    record(
+      &mut new,
+      Platform::Amazon,
+      &AMAZON_REGEX,
+      replace_amazon,
+      reps,
+    );
+    record(
+      &mut new,
+      Platform::Amazon,
+      &AMAZON_SEARCH_REGEX,
+      replace_amazon_search,
+      reps,
+    );
+  }
+  if enabled(Platform::Weixin) {
+    record(
+      &mut new,
+      Platform::Weixin,
+      &WEIXIN_REGEX,
+      replace_weixin,
+      reps,
+    );
+  }
+  if enabled(Platform::Jd) {
+    record(&mut new, Platform::Jd, &JD_REGEX, replace_jd, reps);
+  }
+  if enabled(Platform::Instagram) {
+    record(
+      &mut new,
+      Platform::Instagram,
+      &INSTAGRAM_REGEX,
+      replace_instagram,
+      reps,
+    );
+  }
+  if enabled(Platform::Reddit) {
+    record(
+      &mut new,
+      Platform::Reddit,
+      &REDDIT_REGEX,
+      replace_reddit,
+      reps,
+    );
+  }
+  if enabled(Platform::Aliexpress) {
+    record(
+      &mut new,
+      Platform::Aliexpress,
+      &ALIEXPRESS_REGEX,
+      replace_aliexpress,
+      reps,
+    );
+  }
+  if enabled(Platform::Taobao) {
+    record(
+      &mut new,
+      Platform::Taobao,
+      &TAOBAO_REGEX,
+      in_place(replace_taobao),
+      reps,
+    );
+  }
+  if enabled(Platform::Spotify) {
+    record(
+      &mut new,
+      Platform::Spotify,
+      &SPOTIFY_REGEX,
+      replace_spotify,
+      reps,
+    );
+  }
+  if enabled(Platform::Facebook) {
+    record(
+      &mut new,
+      Platform::Facebook,
+      &FACEBOOK_REGEX,
+      replace_facebook,
+      reps,
+    );
+  }
+  if enabled(Platform::Pixiv) {
+    record(&mut new, Platform::Pixiv, &PIXIV_REGEX, replace_pixiv, reps);
+  }
+  if enabled(Platform::Github) {
+    record(
+      &mut new,
+      Platform::Github,
+      &GITHUB_REGEX,
+      replace_github,
+      reps,
+    );
+  }
+  if enabled(Platform::Discord) {
+    record(
+      &mut new,
+      Platform::Discord,
+      &DISCORD_REGEX,
+      replace_discord,
+      reps,
+    );
+  }
+  if enabled(Platform::Twitch) {
+    record(
+      &mut new,
+      Platform::Twitch,
+      &TWITCH_REGEX,
+      replace_twitch,
+      reps,
+    );
+  }
+  if enabled(Platform::Epic) {
+    record(&mut new, Platform::Epic, &EPIC_REGEX, replace_epic, reps);
+  }
+  if enabled(Platform::Gog) {
+    record(&mut new, Platform::Gog, &GOG_REGEX, replace_gog, reps);
+  }
+  if enabled(Platform::Weibo) {
+    record(&mut new, Platform::Weibo, &WEIBO_REGEX, replace_weibo, reps);
+  }
+  if enabled(Platform::Qq) {
+    record(
+      &mut new,
+      Platform::Qq,
+      &QQ_MIDDLE_REGEX,
+      replace_qq_middle,
+      reps,
+    );
+    record(&mut new, Platform::Qq, &QQDOCS_REGEX, replace_qqdocs, reps);
+  }
+  if enabled(Platform::AppleMusic) {
+    record(
+      &mut new,
+      Platform::AppleMusic,
+      &APPLE_MUSIC_REGEX,
+      replace_apple_music,
+      reps,
+    );
+  }
+  if enabled(Platform::Naver) {
+    record(&mut new, Platform::Naver, &NAVER_REGEX, replace_naver, reps);
+  }
+  if enabled(Platform::Niconico) {
+    record(
+      &mut new,
+      Platform::Niconico,
+      &NICO_REGEX,
+      replace_nicovideo,
+      reps,
+    );
+  }
+  if enabled(Platform::GoogleMaps) {
+    record(
+      &mut new,
+      Platform::GoogleMaps,
+      &GMAPS_REGEX,
+      replace_gmaps,
+      reps,
+    );
+  }
+  if enabled(Platform::Steam) {
+    record(&mut new, Platform::Steam, &STEAM_REGEX, replace_steam, reps);
+    record(
+      &mut new,
+      Platform::Steam,
+      &STEAM_MARKET_REGEX,
+      replace_steam_market,
+      reps,
+    );
+  }
+  if enabled(Platform::Custom) {
+    for rule in &replace_options().custom_rules {
+      record(
+        &mut new,
+        Platform::Custom,
+        &rule.regex,
+        |url| rule.replace(url),
+        reps,
+      );
+    }
+  }
+  // Last, so site rules decide what to keep on their own links.
+  if enabled(Platform::Generic) {
+    record(
+      &mut new,
+      Platform::Generic,
+      &URL_REGEX,
+      |url| strip_common_trackers(url, &replace_options()),
+      reps,
+    );
+  }
+  let mut affiliate_params = Vec::new();
+  for replacement in &replacements {
+    for key in removed_affiliate_params(replacement) {
+      if !affiliate_params.contains(&key) {
+        affiliate_params.push(key);
+      }
+    }
+  }
+  Ok(ReplaceOutcome {
+    changed: new != text,
+    text: new,
+    replacements,
+    affiliate_params,
+  })
+}
+
+/// Short links resolved by [`resolve_short_links`], a range matched by several is claimed by the
+/// first, and the message errors mention which kind failed.
+static SHORT_LINKS: &[(Platform, &Lazy<Regex>, &str)] = &[
+  (
+    Platform::Bilibili,
+    &BSHORT_REGEX,
+    "Failed to replace short url",
+  ),
+  (
+    Platform::Twitter,
+    &TWITTER_SHORT_REGEX,
+    "Failed to replace twitter short url",
+  ),
+  (
+    Platform::Tiktok,
+    &TIKTOK_SHARE_REGEX,
+    "Failed to replace tiktok share url",
+  ),
+  (
+    Platform::Douyin,
+    &DOUYIN_REGEX,
+    "Failed to replace douyin share url",
+  ),
+  (
+    Platform::Reddit,
+    &REDDIT_SHARE_REGEX,
+    "Failed to replace reddit share url",
+  ),
+  (
+    Platform::Niconico,
+    &NICO_SHORT_REGEX,
+    "Failed to replace niconico short url",
+  ),
+  (
+    Platform::GoogleMaps,
+    &GMAPS_SHORT_REGEX,
+    "Failed to replace google maps short url",
+  ),
+  (
+    Platform::Line,
+    &LINEE_SHORT_REGEX,
+    "Failed to replace line short url",
+  ),
+  (
+    Platform::Kakao,
+    &KAKAO_SHORT_REGEX,
+    "Failed to replace kakao short url",
+  ),
+  (
+    Platform::Weibo,
+    &TCN_SHORT_REGEX,
+    "Failed to replace t.cn short url",
+  ),
+  (
+    Platform::Qq,
+    &URLCN_SHORT_REGEX,
+    "Failed to replace url.cn short url",
+  ),
+  (
+    Platform::Naver,
+    &NAVERME_SHORT_REGEX,
+    "Failed to replace naver.me short url",
+  ),
+  (
+    Platform::Amazon,
+    &AMAZON_SHORT_REGEX,
+    "Failed to replace amazon short url",
+  ),
+  (
+    Platform::Aliexpress,
+    &ALIEXPRESS_SHORT_REGEX,
+    "Failed to replace aliexpress short url",
+  ),
+  (
+    Platform::Taobao,
+    &TAOBAO_SHARE_REGEX,
+    "Failed to replace taobao share url",
+  ),
+  (
+    Platform::Facebook,
+    &FBWATCH_REGEX,
+    "Failed to replace fb.watch share url",
+  ),
+  (
+    Platform::Xiaohongshu,
+    &XHSLINK_REGEX,
+    "Failed to replace xhslink short url",
+  ),
+];
+
+/// Resolves the short link `url` of `platform` in [`SHORT_LINKS`].
+async fn resolve_short(
+  platform: Platform,
+  url: &str,
+  resolver: &dyn RedirectResolver,
+) -> Result<String> {
+  match platform {
+    Platform::Bilibili => replace_bshort(url, resolver).await,
+    Platform::Twitter => replace_twitter_short(url, resolver).await,
+    Platform::Tiktok => replace_tiktok_share(url, resolver).await,
+    Platform::Douyin => replace_douyin_share(url, resolver).await,
+    Platform::Reddit => replace_reddit_share(url, resolver).await,
+    Platform::Niconico => replace_nico_short(url, resolver).await,
+    Platform::GoogleMaps => replace_gmaps_short(url, resolver).await,
+    Platform::Line => replace_linee_short(url, resolver).await,
+    Platform::Kakao => replace_kakao_short(url, resolver).await,
+    Platform::Weibo => replace_tcn_short(url, resolver).await,
+    Platform::Qq => replace_urlcn_short(url, resolver).await,
+    Platform::Naver => replace_naverme_short(url, resolver).await,
+    Platform::Amazon => replace_amazon_short(url, resolver).await,
+    Platform::Aliexpress => replace_aliexpress_short(url, resolver).await,
+    Platform::Taobao => replace_taobao_share(url, resolver).await,
+    Platform::Facebook => replace_fbwatch_share(url, resolver).await,
+    Platform::Xiaohongshu => replace_xhslink(url, resolver, replace_options().xhs_keep_token).await,
+    // No short links
+    _ => Ok(url.to_string()),
+  }
+}
+
+/// Resolves the short links in `new` concurrently, a single redirect each, skipping those not
+/// resolved by `deadline`.
+async fn resolve_short_links(
+  new: &mut String,
+  disabled: &HashSet<Platform>,
+  resolver: &dyn RedirectResolver,
+  deadline: Option<tokio::time::Instant>,
+  reps: &mut Vec<Replacement>,
+) -> Result<()> {
+  let blocklist = &replace_options().domain_blocklist;
+  let mut claimed: Vec<(usize, Range<usize>, String)> = Vec::new();
+  for (kind, (platform, regex, _)) in SHORT_LINKS.iter().enumerate() {
+    if disabled.contains(platform) {
+      continue;
+    }
+    for (range, original) in find_matches(regex, new) {
+      let overlaps = claimed
+        .iter()
+        .any(|(_, other, _)| range.start < other.end && other.start < range.end);
+      if !overlaps && !is_in_domains(&original, blocklist) {
+        claimed.push((kind, range, original));
+      }
+    }
+  }
+
+  // A link repeated in the message, say in its quote, is resolved once for all its ranges.
+  let mut distinct: Vec<(usize, &str)> = Vec::new();
+  for (kind, _, original) in &claimed {
+    if !distinct.iter().any(|(_, other)| other == original) {
+      distinct.push((*kind, original));
+    }
+  }
+  let resolving = join_all(distinct.iter().map(|(kind, original)| async move {
+    let resolving = resolve_short(SHORT_LINKS[*kind].0, original, resolver);
+    match deadline {
+      Some(deadline) => tokio::time::timeout_at(deadline, resolving).await.ok(),
+      None => Some(resolving.await),
+    }
+  }))
+  .await;
+  let mut resolved = HashMap::new();
+  for ((kind, original), replaced) in distinct.into_iter().zip(resolving) {
+    match replaced {
+      None => warn!(
+        "Deadline passed resolving {} link {original}, leaving it untouched",
+        SHORT_LINKS[kind].0
+      ),
+      Some(Ok(replaced)) => {
+        resolved.insert(original.to_string(), replaced);
+      },
+      Some(Err(err)) if is_timeout(&err) => {
+        warn!("Timed out resolving {original}, leaving it untouched")
+      },
+      Some(Err(err)) => return Err(err.context(SHORT_LINKS[kind].2)),
+    }
+  }
+  let mut found = Vec::new();
+  for (kind, range, original) in claimed {
+    if let Some(replaced) = resolved.get(&original) {
+      if *replaced != original {
+        found.push((kind, range, original, replaced.clone()));
+      }
+    }
+  }
+
+  // Claimed ranges don't overlap, so replacing from the end keeps the others in place.
+  found.sort_by_key(|(_, range, _, _)| range.start);
+  for (_, range, _, replaced) in found.iter().rev() {
+    new.replace_range(range.clone(), replaced);
+  }
+  found.sort_by_key(|(kind, range, _, _)| (*kind, range.start));
+  reps.extend(
+    found
+      .into_iter()
+      .map(|(kind, _, original, replaced)| Replacement {
+        platform: SHORT_LINKS[kind].0,
+        original,
+        replaced,
+      }),
+  );
+  Ok(())
+}
+
+type Found = Vec<(Range<usize>, String, String)>;
+
+fn find_matches(regex: &Regex, text: &str) -> Vec<(Range<usize>, String)> {
+  regex
+    .find_iter(text)
+    .filter_map(|m| match m {
+      Ok(m) => Some((m.range(), m.as_str().to_string())),
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        None
+      },
+    })
+    .collect()
+}
+
+fn splice(
+  text: &mut String,
+  platform: Platform,
+  found: Found,
+  replacements: &mut Vec<Replacement>,
+) {
+  for (range, _, replaced) in found.iter().rev() {
+    text.replace_range(range.clone(), replaced);
+  }
+  replacements.extend(
+    found
+      .into_iter()
+      .map(|(_, original, replaced)| Replacement {
+        platform,
+        original,
+        replaced,
+      }),
+  );
+}
+
+/// Applies `replace` to every match of `regex` in `text`, recording what changed.
+fn record<F>(
+  text: &mut String,
+  platform: Platform,
+  regex: &Regex,
+  replace: F,
+  replacements: &mut Vec<Replacement>,
+) where
+  F: Fn(&str) -> String,
+{
+  let blocklist = &replace_options().domain_blocklist;
+  let found = find_matches(regex, text)
+    .into_iter()
+    .filter(|(_, original)| !is_in_domains(original, blocklist))
+    .filter_map(|(range, original)| {
+      let replaced = replace(&original);
+      (replaced != original).then_some((range, original, replaced))
+    })
+    .collect();
+  splice(text, platform, found, replacements);
+}
+
+fn is_timeout(err: &anyhow::Error) -> bool {
+  err
+    .downcast_ref::<reqwest::Error>()
+    .is_some_and(|err| err.is_timeout())
+}
+
+fn in_place(replace: fn(&mut String)) -> impl Fn(&str) -> String {
+  move |str| {
+    let mut str = str.to_string();
+    replace(&mut str);
+    str
+  }
+}
+
+fn replace_twitter(url: &str) -> String {
+  TWITTER_REGEX.replace(url, "https://fixupx.com$path").into()
+}
+
+fn replace_twitter_x(url: &str) -> String {
+  TWITTER_X_REGEX
+    .replace(url, "https://fixupx.com$path")
+    .into()
+}
+
+fn replace_weixin(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for i in WEIXIN_REGEX.find_iter(text) {
+    let i = match i {
+      Ok(i) => i,
       Err(err) => {
         error!("Failed to find_iter: {err}");
         continue;
       },
     };
-    let url = get_redirect_url(x.as_str()).await?;
-    new_str.replace_range(x.range(), url.to_string().as_str());
+    let mut url = if let Ok(url) = Url::from_str(i.as_str()) {
+      url
+    } else {
+      continue;
+    };
+    url.keep_pairs_only_in(
+      replace_options().kept_keys(Platform::Weixin, &["__biz", "mid", "idx", "sn"]),
+    );
+    new_str.replace_range(i.range(), url.to_string().as_str());
   }
-  Ok(new_str)
+  new_str
 }
 
-async fn replace_tiktok_share(str: &str) -> Result<String> {
-  let mut new_str = str.to_string();
-  let matches: Vec<_> = TIKTOK_SHARE_REGEX.find_iter(str).collect();
-  for x in matches.iter() {
-    let x = match x {
-      Ok(x) => x,
+fn replace_jd(url: &str) -> String {
+  JD_REGEX.replace_all(url, "$url").into()
+}
+
+fn replace_instagram(url: &str) -> String {
+  INSTAGRAM_REGEX
+    .replace_all(url, "https://www.instagram.com$path")
+    .into()
+}
+
+fn replace_aliexpress(url: &str) -> String {
+  ALIEXPRESS_REGEX
+    .replace_all(url, "https://www.aliexpress.com$path")
+    .into()
+}
+
+fn replace_spotify(url: &str) -> String {
+  SPOTIFY_REGEX
+    .replace_all(url, "https://open.spotify.com$path")
+    .into()
+}
+
+fn replace_pixiv(url: &str) -> String {
+  PIXIV_REGEX
+    .replace_all(url, "https://www.pixiv.net/artworks/$id")
+    .into()
+}
+
+fn replace_nicovideo(url: &str) -> String {
+  NICO_REGEX
+    .replace_all(url, "https://www.nicovideo.jp/watch/$id")
+    .into()
+}
+
+/// Posts need only their path, share links add `type`, `weibo_id` and trackers; links to the
+/// mobile `weibo.cn` are moved to `weibo.com`.
+fn replace_weibo(url: &str) -> String {
+  WEIBO_REGEX
+    .replace_all(url, "https://weibo.com/$path")
+    .into()
+}
+
+/// Blog posts and cafe articles need only their path; links to the mobile site are moved to the
+/// desktop one.
+fn replace_naver(url: &str) -> String {
+  NAVER_REGEX
+    .replace_all(url, "https://$site.naver.com/$path")
+    .into()
+}
+
+fn trim_qqdocs_link(url: &mut Url, options: &ReplaceOptions) {
+  url.keep_pairs_only_in(options.kept_keys(Platform::Qq, &["tab"]));
+}
+
+/// Tencent docs need only the doc id in their path, and `tab` to open a sheet on the shared tab.
+fn replace_qqdocs(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for i in QQDOCS_REGEX
+    .find_iter(text)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+  {
+    let i = match i {
+      Ok(i) => i,
       Err(err) => {
         error!("Failed to find_iter: {err}");
         continue;
       },
     };
-    let mut url = get_redirect_url(x.as_str()).await?;
-    url.set_query(None);
-    new_str.replace_range(x.range(), url.to_string().as_str());
+    let Some(mut url) = parse_link(i.as_str()) else {
+      continue;
+    };
+    trim_qqdocs_link(&mut url, &replace_options());
+    new_str.replace_range(i.range(), url.as_str());
+  }
+  new_str
+}
+
+/// QQ opens outside links through an interstitial page, with the link itself in `pfurl`.
+fn replace_qq_middle(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for i in QQ_MIDDLE_REGEX
+    .find_iter(text)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+  {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Some(url) = parse_link(i.as_str()) else {
+      continue;
+    };
+    let Some((_, target)) = url.query_pairs().find(|(k, _)| k == "pfurl" || k == "url") else {
+      continue;
+    };
+    if target.starts_with("http://") || target.starts_with("https://") {
+      new_str.replace_range(i.range(), &target);
+    }
+  }
+  new_str
+}
+
+fn trim_apple_music_link(url: &mut Url, options: &ReplaceOptions) {
+  url.keep_pairs_only_in(options.kept_keys(Platform::AppleMusic, &["i"]));
+}
+
+/// Album and podcast links point at a track or episode with `i`, everything else, e.g. `ls` and
+/// `uo`, only tracks the share.
+fn replace_apple_music(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for i in APPLE_MUSIC_REGEX
+    .find_iter(text)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+  {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Some(mut url) = parse_link(i.as_str()) else {
+      continue;
+    };
+    trim_apple_music_link(&mut url, &replace_options());
+    new_str.replace_range(i.range(), url.as_str());
+  }
+  new_str
+}
+
+fn trim_github_link(url: &mut Url, options: &ReplaceOptions) {
+  const KEYS: &[&str] = &[
+    "tab", "q", "type", "page", "sort", "state", "plain", "w", "diff",
+  ];
+  url.keep_pairs_only_in(options.kept_keys(Platform::Github, KEYS));
+}
+
+fn replace_github(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for i in GITHUB_REGEX
+    .find_iter(text)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+  {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Some(mut url) = parse_link(i.as_str()) else {
+      continue;
+    };
+    trim_github_link(&mut url, &replace_options());
+    new_str.replace_range(i.range(), url.as_str());
+  }
+  new_str
+}
+
+fn replace_steam(url: &str) -> String {
+  STEAM_REGEX
+    .replace_all(url, "https://store.steampowered.com$path")
+    .into()
+}
+
+fn replace_steam_market(url: &str) -> String {
+  STEAM_MARKET_REGEX
+    .replace_all(url, "https://steamcommunity.com$path")
+    .into()
+}
+
+/// Invites and message links need nothing but their path.
+fn replace_discord(url: &str) -> String {
+  DISCORD_REGEX.replace_all(url, "https://$host$path").into()
+}
+
+/// Drops the locale too, the store picks the visitor's own.
+fn replace_epic(url: &str) -> String {
+  EPIC_REGEX
+    .replace_all(url, "https://store.epicgames.com$path")
+    .into()
+}
+
+fn replace_gog(url: &str) -> String {
+  GOG_REGEX
+    .replace_all(url, "https://www.gog.com$path")
+    .into()
+}
+
+fn replace_amazon(url: &str) -> String {
+  AMAZON_REGEX.replace_all(url, "$domain$path").into()
+}
+
+fn replace_amazon_search(url: &str) -> String {
+  AMAZON_SEARCH_REGEX
+    .replace_all(url, "$domain$keyword")
+    .into()
+}
+
+fn trim_youtube_link(url: &mut Url, options: &ReplaceOptions) {
+  url.keep_pairs_only_in(options.kept_keys(Platform::Youtube, &["v", "list", "index", "t"]));
+}
+
+/// Moves mobile and music links to `www.youtube.com`, and `youtu.be/<id>` or `/shorts/<id>` to
+/// `/watch?v=<id>`, keeping the rest of the query.
+fn canonicalize_youtube_link(url: &mut Url) {
+  let id = match url.host_str() {
+    Some("youtu.be") => url.path().trim_start_matches('/').to_string(),
+    _ => match url.path().strip_prefix("/shorts/") {
+      Some(id) => id.trim_end_matches('/').to_string(),
+      None => String::new(),
+    },
+  };
+  let rewrite = !id.is_empty() && !id.contains('/');
+  if rewrite || matches!(url.host_str(), Some("m.youtube.com" | "music.youtube.com")) {
+    let _ = url.set_host(Some("www.youtube.com"));
+  }
+  if !rewrite {
+    return;
+  }
+  let pairs = url
+    .query_pairs()
+    .into_owned()
+    .filter(|(key, _)| key != "v")
+    .collect::<Vec<_>>();
+  url.set_path("/watch");
+  url
+    .query_pairs_mut()
+    .clear()
+    .append_pair("v", &id)
+    .extend_pairs(pairs);
+}
+
+fn replace_youtube(text: &mut String) {
+  let mut replaces = Vec::new();
+  for i in YOUTUBE_REGEX.find_iter(text) {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Ok(mut url) = Url::from_str(i.as_str()) else {
+      continue;
+    };
+    canonicalize_youtube_link(&mut url);
+    trim_youtube_link(&mut url, &replace_options());
+    replaces.push((i.range(), url.to_string()));
+  }
+  for (range, str) in replaces.into_iter().rev() {
+    text.replace_range(range, str.as_str());
+  }
+}
+
+fn trim_taobao_link(url: &mut Url, options: &ReplaceOptions) {
+  url.keep_pairs_only_in(options.kept_keys(Platform::Taobao, &["id"]));
+}
+
+fn replace_taobao(text: &mut String) {
+  let mut replaces = Vec::new();
+  for i in TAOBAO_REGEX.find_iter(text) {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Ok(mut url) = Url::from_str(i.as_str()) else {
+      continue;
+    };
+    trim_taobao_link(&mut url, &replace_options());
+    replaces.push((i.range(), url.to_string()));
+  }
+  for (range, str) in replaces.into_iter().rev() {
+    text.replace_range(range, str.as_str());
+  }
+}
+
+/// Taobao redirects unauthenticated clients to a login page instead of the item.
+fn is_taobao_login_wall(url: &Url) -> bool {
+  url
+    .host_str()
+    .is_some_and(|host| host.starts_with("login."))
+}
+
+/// Whether `url` is a xiaohongshu note, rather than e.g. a login or error page.
+fn is_xhs_note(url: &Url) -> bool {
+  let segments = url
+    .path_segments()
+    .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+    .unwrap_or_default();
+  url.host_str() == Some("www.xiaohongshu.com")
+    && matches!(
+      segments.as_slice(),
+      ["explore", _] | ["discovery", "item", _]
+    )
+}
+
+fn trim_xhs_link(url: &mut Url, keep_token: bool) {
+  let keys: &[&str] = if keep_token { &["xsec_token"] } else { &[] };
+  url.keep_pairs_only_in(Cow::Borrowed(keys));
+}
+
+fn trim_facebook_link(url: &mut Url, options: &ReplaceOptions) {
+  url.keep_pairs_only_in(options.kept_keys(
+    Platform::Facebook,
+    &["v", "id", "story_fbid", "fbid", "set"],
+  ));
+  if url.host_str() != Some("www.facebook.com") {
+    let _ = url.set_host(Some("www.facebook.com"));
+  }
+}
+
+fn replace_facebook(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for i in FACEBOOK_REGEX
+    .find_iter(text)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+  {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Ok(mut url) = Url::from_str(i.as_str()) else {
+      continue;
+    };
+    trim_facebook_link(&mut url, &replace_options());
+    new_str.replace_range(i.range(), url.as_str());
+  }
+  new_str
+}
+
+/// Query keys kept on bilibili links by section, other sections are trimmed like videos.
+pub const BILI_KEPT_KEYS: &[(&str, &[&str])] = &[
+  ("video", &["p", "t", "start_progress"]),
+  ("live", &[]),
+  ("space", &[]),
+  ("dynamic", &[]),
+  ("opus", &[]),
+  ("read", &[]),
+  ("bangumi", &[]),
+  ("cheese", &[]),
+];
+
+/// The section of a bilibili link, its subdomain for `live`, `space` and `t`, otherwise the first
+/// path segment.
+fn bili_section(url: &Url) -> &str {
+  match url
+    .host_str()
+    .and_then(|host| host.strip_suffix(".bilibili.com"))
+  {
+    Some("live") => "live",
+    Some("space") => "space",
+    Some("t") => "dynamic",
+    _ => url
+      .path_segments()
+      .and_then(|mut segments| segments.next())
+      .unwrap_or_default(),
+  }
+}
+
+/// Trims any bilibili link, keeping the query keys of its section in [`BILI_KEPT_KEYS`] unless
+/// configured otherwise.
+fn trim_generic_bili(url: &mut Url, options: &ReplaceOptions) {
+  let (section, kept) = BILI_KEPT_KEYS
+    .iter()
+    .find(|(name, _)| *name == bili_section(url))
+    .unwrap_or(&BILI_KEPT_KEYS[0]);
+  url.keep_pairs_only_in(options.kept_section_keys(Platform::Bilibili, section, kept));
+}
+
+/// Rewrites bilibili links matching `regex` to `canonical`, which may refer to groups of the
+/// regex, then trims them with [`trim_generic_bili`].
+fn replace_bili_canonical(text: &str, regex: &Regex, canonical: &str) -> String {
+  regex
+    .replace_all(text, |caps: &Captures| {
+      let matched = caps.get(0).map_or("", |m| m.as_str());
+      let mut link = String::new();
+      caps.expand(canonical, &mut link);
+      if let Some(at) = matched.find('?') {
+        link.push_str(&matched[at..]);
+      }
+      match Url::parse(&link) {
+        Ok(mut url) => {
+          trim_generic_bili(&mut url, &replace_options());
+          url.into()
+        },
+        Err(_) => matched.to_string(),
+      }
+    })
+    .into()
+}
+
+/// Parses a link matched with or without its scheme, taking `https` when it lacks one.
+fn parse_link(link: &str) -> Option<Url> {
+  if link.contains("://") {
+    Url::parse(link).ok()
+  } else {
+    Url::parse(&format!("https://{link}")).ok()
+  }
+}
+
+/// Whether the host of `url`, which may lack a scheme, is in `domains` or a subdomain of one.
+fn is_in_domains(url: &str, domains: &[String]) -> bool {
+  if domains.is_empty() {
+    return false;
+  }
+  let Some(host) = parse_link(url).and_then(|url| url.host_str().map(str::to_string)) else {
+    return false;
+  };
+  domains.iter().any(|domain| {
+    host == *domain
+      || host
+        .strip_suffix(domain.as_str())
+        .is_some_and(|sub| sub.ends_with('.'))
+  })
+}
+
+/// Removes [`COMMON_TRACKERS`] and the configured extra ones from `url`, and from its fragment on
+/// `fragment_tracker_domains`, leaving it as is when it has none.
+fn strip_common_trackers(url: &str, options: &ReplaceOptions) -> String {
+  if is_in_domains(url, &options.domain_blocklist) {
+    return url.to_string();
+  }
+  let is_tracker = |key: &str| {
+    COMMON_TRACKERS.contains(&key) || options.extra_trackers.iter().any(|extra| extra == key)
+  };
+  let Ok(mut parsed) = Url::from_str(url) else {
+    return url.to_string();
+  };
+  let mut changed = false;
+  if parsed.query_pairs().any(|(key, _)| is_tracker(&key)) {
+    parsed.remove_pairs_if_key(is_tracker);
+    changed = true;
+  }
+  if is_in_domains(url, &options.fragment_tracker_domains) {
+    changed |= parsed.remove_fragment_pairs_if_key(is_tracker);
+  }
+  if !changed {
+    return url.to_string();
+  }
+  parsed.into()
+}
+
+fn trim_twitch_link(url: &mut Url, options: &ReplaceOptions) {
+  url.keep_pairs_only_in(options.kept_keys(Platform::Twitch, &["t"]));
+}
+
+fn trim_gmaps_link(url: &mut Url, options: &ReplaceOptions) {
+  const KEYS: &[&str] = &[
+    "q",
+    "query",
+    "query_place_id",
+    "ll",
+    "z",
+    "cid",
+    "ftid",
+    "center",
+    "zoom",
+    "origin",
+    "destination",
+    "api",
+  ];
+  url.keep_pairs_only_in(options.kept_keys(Platform::GoogleMaps, KEYS));
+}
+
+/// Strips `entry`, `g_ep` and such, coordinates and place ids in the path are left as is.
+fn replace_gmaps(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for caps in GMAPS_REGEX
+    .captures_iter(text)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+  {
+    let caps = match caps {
+      Ok(caps) => caps,
+      Err(err) => {
+        error!("Failed to captures_iter: {err}");
+        continue;
+      },
+    };
+    let Some(query) = caps.name("query") else {
+      continue;
+    };
+    let url = caps.name("url").map_or("", |m| m.as_str());
+    let Ok(mut url) = Url::from_str(&format!("https://{url}{}", query.as_str())) else {
+      continue;
+    };
+    trim_gmaps_link(&mut url, &replace_options());
+    new_str.replace_range(caps.get(0).unwrap().range(), url.as_str());
+  }
+  new_str
+}
+
+/// Strips `tt_content`, `tt_medium` and such, moving mobile links to `www.twitch.tv`.
+fn replace_twitch(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for caps in TWITCH_REGEX
+    .captures_iter(text)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+  {
+    let caps = match caps {
+      Ok(caps) => caps,
+      Err(err) => {
+        error!("Failed to captures_iter: {err}");
+        continue;
+      },
+    };
+    let host = caps.name("host").map_or("", |m| m.as_str());
+    let path = caps.name("path").map_or("", |m| m.as_str());
+    let query = caps.name("query").map_or("", |m| m.as_str());
+    let host = match host {
+      "m.twitch.tv" => "www.twitch.tv",
+      // Nothing to clean
+      _ if query.is_empty() => continue,
+      host => host,
+    };
+    let Ok(mut url) = Url::from_str(&format!("https://{host}{path}{query}")) else {
+      continue;
+    };
+    trim_twitch_link(&mut url, &replace_options());
+    new_str.replace_range(caps.get(0).unwrap().range(), url.as_str());
+  }
+  new_str
+}
+
+/// Festival pages embed the video they play as `bvid`.
+fn replace_bfestival(text: &str) -> String {
+  let mut new_str = text.to_string();
+  for i in BFESTIVAL_REGEX
+    .find_iter(text)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+  {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Ok(url) = Url::from_str(i.as_str()) else {
+      continue;
+    };
+    let Some((_, bvid)) = url.query_pairs().find(|(k, _)| k == "bvid") else {
+      continue;
+    };
+    new_str.replace_range(i.range(), &format!("https://www.bilibili.com/video/{bvid}"));
+  }
+  new_str
+}
+
+fn replace_btrack(text: &mut String) {
+  let mut replaces = Vec::new();
+  for i in BVIDEO_REGEX.find_iter(text) {
+    let i = match i {
+      Ok(i) => i,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let Ok(mut url) = Url::from_str(i.as_str()) else {
+      continue;
+    };
+    trim_generic_bili(&mut url, &replace_options());
+    replaces.push((i.range(), url.to_string()));
+  }
+  for (range, str) in replaces {
+    text.replace_range(range, str.as_str());
+  }
+}
+
+async fn replace_bshort(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = BSHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let mut url = resolver.resolve(x.as_str()).await?;
+    trim_generic_bili(&mut url, &replace_options());
+    new_str.replace_range(x.range(), url.to_string().as_str());
+  }
+  Ok(new_str)
+}
+
+async fn replace_twitter_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = TWITTER_SHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    new_str.replace_range(x.range(), url.to_string().as_str());
+  }
+  Ok(new_str)
+}
+
+async fn replace_tiktok_share(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = TIKTOK_SHARE_REGEX.find_iter(str).collect();
+  for x in matches.iter() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let mut url = resolver.resolve(x.as_str()).await?;
+    url.set_query(None);
+    new_str.replace_range(x.range(), url.to_string().as_str());
+  }
+  Ok(new_str)
+}
+
+/// Douyin may stop at its `iesdouyin.com/share/video/<id>` page, which is the same video.
+fn trim_douyin_link(url: &mut Url) {
+  url.set_query(None);
+  let id = match url
+    .path_segments()
+    .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+    .as_deref()
+  {
+    Some(["share", "video", id] | ["video", id]) => id.to_string(),
+    _ => return,
+  };
+  if let Ok(video) = Url::parse(&format!("https://www.douyin.com/video/{id}")) {
+    *url = video;
+  }
+}
+
+async fn replace_douyin_share(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = DOUYIN_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let mut url = resolver.resolve(x.as_str()).await?;
+    trim_douyin_link(&mut url);
+    new_str.replace_range(x.range(), url.to_string().as_str());
+  }
+  Ok(new_str)
+}
+
+async fn replace_reddit_share(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = REDDIT_SHARE_REGEX.find_iter(str).collect();
+  for x in matches.iter() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let mut url = resolver.resolve(x.as_str()).await?;
+    url.set_query(None);
+    new_str.replace_range(x.range(), url.to_string().as_str());
+  }
+  Ok(new_str)
+}
+
+fn replace_reddit(url: &str) -> String {
+  REDDIT_REGEX.replace_all(url, "$url").into()
+}
+
+async fn replace_amazon_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = AMAZON_SHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    new_str.replace_range(x.range(), &replace_amazon(url.as_str()));
+  }
+  Ok(new_str)
+}
+
+async fn replace_nico_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = NICO_SHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    new_str.replace_range(x.range(), &replace_nicovideo(url.as_str()));
+  }
+  Ok(new_str)
+}
+
+async fn replace_gmaps_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = GMAPS_SHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    new_str.replace_range(x.range(), &replace_gmaps(url.as_str()));
+  }
+  Ok(new_str)
+}
+
+/// Resolves every match of `regex` in `str`, keeping only the non-tracking query of the targets.
+async fn resolve_and_strip_trackers(
+  str: &str,
+  regex: &Regex,
+  resolver: &dyn RedirectResolver,
+) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = regex.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    new_str.replace_range(
+      x.range(),
+      &strip_common_trackers(url.as_str(), &replace_options()),
+    );
+  }
+  Ok(new_str)
+}
+
+async fn replace_linee_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  resolve_and_strip_trackers(str, &LINEE_SHORT_REGEX, resolver).await
+}
+
+async fn replace_kakao_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  resolve_and_strip_trackers(str, &KAKAO_SHORT_REGEX, resolver).await
+}
+
+/// `t.cn` may point anywhere, links to weibo are cleaned right away.
+async fn replace_tcn_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = TCN_SHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    let url = strip_common_trackers(url.as_str(), &replace_options());
+    new_str.replace_range(x.range(), &replace_weibo(&url));
+  }
+  Ok(new_str)
+}
+
+/// `naver.me` may point anywhere, links to naver blogs and cafes are cleaned right away.
+async fn replace_naverme_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = NAVERME_SHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    let url = strip_common_trackers(url.as_str(), &replace_options());
+    new_str.replace_range(x.range(), &replace_naver(&url));
+  }
+  Ok(new_str)
+}
+
+async fn replace_urlcn_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = URLCN_SHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    let url = strip_common_trackers(url.as_str(), &replace_options());
+    new_str.replace_range(x.range(), &replace_qqdocs(&url));
+  }
+  Ok(new_str)
+}
+
+async fn replace_aliexpress_short(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = ALIEXPRESS_SHORT_REGEX.find_iter(str).collect();
+  for x in matches.iter() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let url = resolver.resolve(x.as_str()).await?;
+    new_str.replace_range(x.range(), &replace_aliexpress(url.as_str()));
+  }
+  Ok(new_str)
+}
+
+async fn replace_taobao_share(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = TAOBAO_SHARE_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let mut url = resolver.resolve(x.as_str()).await?;
+    if is_taobao_login_wall(&url) {
+      continue;
+    }
+    trim_taobao_link(&mut url, &replace_options());
+    new_str.replace_range(x.range(), url.to_string().as_str());
+  }
+  Ok(new_str)
+}
+
+async fn replace_xhslink(
+  str: &str,
+  resolver: &dyn RedirectResolver,
+  keep_token: bool,
+) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = XHSLINK_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let mut url = resolver.resolve(x.as_str()).await?;
+    if !is_xhs_note(&url) {
+      continue;
+    }
+    trim_xhs_link(&mut url, keep_token);
+    new_str.replace_range(x.range(), url.to_string().as_str());
+  }
+  Ok(new_str)
+}
+
+async fn replace_fbwatch_share(str: &str, resolver: &dyn RedirectResolver) -> Result<String> {
+  let mut new_str = str.to_string();
+  let matches: Vec<_> = FBWATCH_REGEX.find_iter(str).collect();
+  for x in matches.iter().rev() {
+    let x = match x {
+      Ok(x) => x,
+      Err(err) => {
+        error!("Failed to find_iter: {err}");
+        continue;
+      },
+    };
+    let mut url = resolver.resolve(x.as_str()).await?;
+    trim_facebook_link(&mut url, &replace_options());
+    new_str.replace_range(x.range(), url.as_str());
+  }
+  Ok(new_str)
+}
+
+fn replace_barticle(str: &str) -> String {
+  replace_bili_canonical(
+    str,
+    &BARTICLE_REGEX,
+    "https://www.bilibili.com/read/cv$cvid",
+  )
+}
+
+fn replace_bopus(str: &str) -> String {
+  replace_bili_canonical(str, &BOPUS_REGEX, "https://t.bilibili.com/$opus_id")
+}
+
+fn replace_bbangumi(str: &str) -> String {
+  replace_bili_canonical(
+    str,
+    &BBANGUMI_REGEX,
+    "https://www.bilibili.com/bangumi/play/$id",
+  )
+}
+
+fn replace_bcheese(str: &str) -> String {
+  replace_bili_canonical(
+    str,
+    &BCHEESE_REGEX,
+    "https://www.bilibili.com/cheese/play/$id",
+  )
+}
+
+fn replace_bspace(str: &str) -> String {
+  replace_bili_canonical(str, &BSPACE_REGEX, "https://space.bilibili.com/$path")
+}
+
+fn replace_bdynamic(str: &str) -> String {
+  replace_bili_canonical(str, &BDYNAMIC_REGEX, "https://t.bilibili.com/$dynamic_id")
+}
+
+fn replace_blive(str: &str) -> String {
+  replace_bili_canonical(str, &BLIVE_REGEX, "https://live.bilibili.com/$room_id")
+}
+
+/// Resolves a short link to where it redirects.
+#[async_trait]
+pub trait RedirectResolver: Send + Sync {
+  async fn resolve(&self, url: &str) -> Result<Url>;
+}
+
+/// Resolves short links over HTTP, following a single redirect unless configured otherwise.
+pub struct ReqwestResolver {
+  client: Client,
+}
+
+#[async_trait]
+impl RedirectResolver for ReqwestResolver {
+  async fn resolve(&self, url: &str) -> Result<Url> {
+    resolve_with(&self.client, url).await
+  }
+}
+
+/// Resolves each distinct url at most once.
+struct Memoized<'a> {
+  inner: &'a dyn RedirectResolver,
+  resolved: Mutex<HashMap<String, Url>>,
+}
+
+impl<'a> Memoized<'a> {
+  fn new(inner: &'a dyn RedirectResolver) -> Self {
+    Self {
+      inner,
+      resolved: Mutex::new(HashMap::new()),
+    }
+  }
+}
+
+#[async_trait]
+impl RedirectResolver for Memoized<'_> {
+  async fn resolve(&self, url: &str) -> Result<Url> {
+    let cached = self.resolved.lock().unwrap().get(url).cloned();
+    if let Some(resolved) = cached {
+      return Ok(resolved);
+    }
+    let resolved = self.inner.resolve(url).await?;
+    self
+      .resolved
+      .lock()
+      .unwrap()
+      .insert(url.to_string(), resolved.clone());
+    Ok(resolved)
+  }
+}
+
+/// Reuses resolved urls across messages for `ttl`, keeping at most `capacity` of them.
+pub struct CachedResolver<R> {
+  inner: R,
+  ttl: Duration,
+  capacity: usize,
+  resolved: Mutex<HashMap<String, (Url, Instant)>>,
+}
+
+impl<R> CachedResolver<R> {
+  pub fn new(inner: R, ttl: Duration, capacity: usize) -> Self {
+    Self {
+      inner,
+      ttl,
+      capacity,
+      resolved: Mutex::new(HashMap::new()),
+    }
+  }
+
+  fn get(&self, url: &str) -> Option<Url> {
+    let mut resolved = self.resolved.lock().unwrap();
+    match resolved.get(url) {
+      Some((target, at)) if at.elapsed() < self.ttl => Some(target.clone()),
+      Some(_) => {
+        resolved.remove(url);
+        None
+      },
+      None => None,
+    }
+  }
+
+  fn insert(&self, url: &str, target: Url) {
+    if self.ttl.is_zero() || self.capacity == 0 {
+      return;
+    }
+    let mut resolved = self.resolved.lock().unwrap();
+    if resolved.len() >= self.capacity {
+      resolved.retain(|_, (_, at)| at.elapsed() < self.ttl);
+    }
+    if resolved.len() >= self.capacity {
+      let oldest = resolved
+        .iter()
+        .min_by_key(|(_, (_, at))| *at)
+        .map(|(url, _)| url.clone());
+      if let Some(oldest) = oldest {
+        resolved.remove(&oldest);
+      }
+    }
+    resolved.insert(url.to_string(), (target, Instant::now()));
+  }
+}
+
+#[async_trait]
+impl<R: RedirectResolver> RedirectResolver for CachedResolver<R> {
+  async fn resolve(&self, url: &str) -> Result<Url> {
+    if let Some(target) = self.get(url) {
+      return Ok(target);
+    }
+    let target = self.inner.resolve(url).await?;
+    self.insert(url, target.clone());
+    Ok(target)
+  }
+}
+
+async fn resolve_with(client: &Client, url: &str) -> Result<Url> {
+  let start = Instant::now();
+  let resp = client.get(url).send().await;
+  metrics::histogram!(REDIRECT_LATENCY_METRIC).record(start.elapsed().as_secs_f64());
+  let resp = resp.with_context(|| format!("Failed to get url {url}"))?;
+  Ok(resp.url().clone())
+}
+
+trait RemovePairsIf {
+  fn remove_pairs_if_key<P>(&mut self, predicate: P)
+  where
+    Self: Sized,
+    P: Fn(&str) -> bool;
+
+  /// Like [`remove_pairs_if_key`](Self::remove_pairs_if_key) on a fragment of `key=value`
+  /// pairs, or on the query of a hash route like `#/item?key=value`, returning whether any was
+  /// removed. Other fragments, e.g. `#section-2`, are kept as they are.
+  fn remove_fragment_pairs_if_key<P>(&mut self, predicate: P) -> bool
+  where
+    Self: Sized,
+    P: Fn(&str) -> bool;
+
+  #[inline]
+  fn keep_pairs_only_in(&mut self, vec: Cow<[&str]>)
+  where
+    Self: Sized,
+  {
+    self.remove_pairs_if_key(|k| !vec.contains(&k));
+  }
+}
+
+impl RemovePairsIf for Url {
+  #[inline]
+  fn remove_pairs_if_key<P>(&mut self, predicate: P)
+  where
+    Self: Sized,
+    P: Fn(&str) -> bool,
+  {
+    let buf = String::new();
+    let mut ser = form_urlencoded::Serializer::new(buf);
+    self.query_pairs().into_iter().for_each(|(k, v)| {
+      if !predicate(k.borrow()) {
+        ser.append_pair(k.borrow(), v.borrow());
+      }
+    });
+
+    self.set_query(match &*ser.finish() {
+      "" => None,
+      query => Some(query),
+    });
+  }
+
+  fn remove_fragment_pairs_if_key<P>(&mut self, predicate: P) -> bool
+  where
+    Self: Sized,
+    P: Fn(&str) -> bool,
+  {
+    let Some(fragment) = self.fragment() else {
+      return false;
+    };
+    let (route, pairs) = match fragment.split_once('?') {
+      Some((route, pairs)) => (route, pairs),
+      None => ("", fragment),
+    };
+    if pairs.is_empty() || !pairs.split('&').all(|pair| pair.contains('=')) {
+      return false;
+    }
+    if !form_urlencoded::parse(pairs.as_bytes()).any(|(k, _)| predicate(k.borrow())) {
+      return false;
+    }
+    let mut ser = form_urlencoded::Serializer::new(String::new());
+    form_urlencoded::parse(pairs.as_bytes()).for_each(|(k, v)| {
+      if !predicate(k.borrow()) {
+        ser.append_pair(k.borrow(), v.borrow());
+      }
+    });
+    let fragment = match (route, &*ser.finish()) {
+      ("", "") => None,
+      ("", pairs) => Some(pairs.to_string()),
+      (route, "") => Some(route.to_string()),
+      (route, pairs) => Some(format!("{route}?{pairs}")),
+    };
+    self.set_fragment(fragment.as_deref());
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn remove_all() {
+    let mut text = "https://www.bilibili.com/video/BV1Hg411T7fT/?spm_id_from=333.788.recommend_more_video.1&vd_source=425ad7d352481d80617a03327da07da0".to_string();
+    replace_btrack(&mut text);
+    assert_eq!("https://www.bilibili.com/video/BV1Hg411T7fT/", text);
+  }
+
+  #[test]
+  fn keep_certain_params() {
+    {
+      let mut text =
+        "https://www.bilibili.com/video/BV114514/?t=123&p=1&spm=1.2212.22321".to_string();
+      replace_btrack(&mut text);
+      assert_eq!("https://www.bilibili.com/video/BV114514/?t=123&p=1", text);
+    }
+    {
+      let mut text = "https://www.bilibili.com/video/BV114514/?t=123&spm=1.2212.22321".to_string();
+      replace_btrack(&mut text);
+      assert_eq!("https://www.bilibili.com/video/BV114514/?t=123", text);
+    }
+  }
+
+  #[test]
+  fn trim_bili_sections() {
+    let trim = |url: &str| {
+      let mut url = Url::from_str(url).unwrap();
+      trim_generic_bili(&mut url, &ReplaceOptions::default());
+      url.to_string()
+    };
+    assert_eq!(
+      "https://www.bilibili.com/video/BV114514?p=2&start_progress=42000",
+      trim("https://www.bilibili.com/video/BV114514?p=2&start_progress=42000&share_source=COPY&vd_source=abc")
+    );
+    assert_eq!(
+      "https://live.bilibili.com/12345",
+      trim("https://live.bilibili.com/12345?broadcast_type=0&is_room_feed=1&t=10")
+    );
+    assert_eq!(
+      "https://space.bilibili.com/12345/video",
+      trim("https://space.bilibili.com/12345/video?spm_id_from=333.999.0.0&p=2")
+    );
+    assert_eq!(
+      "https://m.bilibili.com/dynamic/114514",
+      trim("https://m.bilibili.com/dynamic/114514?share_source=COPY")
+    );
+
+    let options = ReplaceOptions {
+      keep_params: HashMap::from([(
+        Platform::Bilibili,
+        KeepParams::Sections(HashMap::from([("live".to_string(), vec!["t".to_string()])])),
+      )]),
+      ..Default::default()
+    };
+    let trim = |url: &str| {
+      let mut url = Url::from_str(url).unwrap();
+      trim_generic_bili(&mut url, &options);
+      url.to_string()
+    };
+    assert_eq!(
+      "https://live.bilibili.com/12345?t=10",
+      trim("https://live.bilibili.com/12345?broadcast_type=0&t=10")
+    );
+    assert_eq!(
+      "https://www.bilibili.com/video/BV114514?t=10",
+      trim("https://www.bilibili.com/video/BV114514?t=10&vd_source=abc")
+    );
+    assert_eq!(
+      "https://space.bilibili.com/12345",
+      trim("https://space.bilibili.com/12345?t=10")
+    );
+  }
+
+  #[tokio::test]
+  async fn disabled_replacers() {
+    let options = ReplaceOptions {
+      replacers: HashMap::from([(Platform::Amazon, false), (Platform::Youtube, true)]),
+      ..Default::default()
+    };
+    assert!(!options.is_enabled(Platform::Amazon));
+    assert!(options.is_enabled(Platform::Youtube));
+    assert!(options.is_enabled(Platform::Twitter));
+
+    let disabled = options.with_disabled(&HashSet::from([Platform::Twitter]));
+    assert_eq!(
+      HashSet::from([Platform::Amazon, Platform::Twitter]),
+      disabled
+    );
+    let url = "https://www.amazon.com/dp/B0C1234567/?tag=someone-20&ref=sr_1_1";
+    let outcome = replace_all_with_resolver(url, &disabled, &mock(""))
+      .await
+      .unwrap();
+    assert!(!outcome.changed);
+    assert_eq!(url, outcome.text);
+  }
+
+  #[tokio::test]
+  async fn bshort() {
+    let text = "https://b23.tv/lBI8Ov3".to_string();
+    let resolver = mock("https://www.bilibili.com/video/BV1se4y177g9/?share_source=copy_web&t=100&vd_source=0123456789abcdef");
+    let result = replace_bshort(&text, &resolver).await.unwrap();
+    assert_eq!("https://www.bilibili.com/video/BV1se4y177g9/?t=100", result);
+  }
+
+  #[tokio::test]
+  async fn bshort_app_share_params() {
+    let resolver = mock("https://www.bilibili.com/video/BV1se4y177g9?buvid=XY1234567890ABCDEF&is_story_h5=false&mid=abc%3D%3D&p=2&plat_id=116&share_from=ugc&share_medium=android&share_plat=android&share_session_id=0a1b2c3d&share_source=COPY&share_tag=s_i&spmid=united.player-video-detail.0.0&t=42&timestamp=1700000000&unique_k=AbCdEf1&up_id=123456");
+    assert_eq!(
+      "https://www.bilibili.com/video/BV1se4y177g9?p=2&t=42",
+      replace_bshort("https://b23.tv/AbCdEf1", &resolver)
+        .await
+        .unwrap()
+    );
+
+    let resolver = mock("https://www.bilibili.com/video/BV1se4y177g9/?buvid=XY1234567890ABCDEF&share_source=COPY&unique_k=AbCdEf1#reply123456");
+    assert_eq!(
+      "https://www.bilibili.com/video/BV1se4y177g9/#reply123456",
+      replace_bshort("https://b23.tv/AbCdEf1", &resolver)
+        .await
+        .unwrap()
+    );
+  }
+
+  #[test]
+  fn amazon() {
+    assert_eq!(
+      "https://www.amazon.com/dp/B00NLZUM36/",
+      replace_amazon("https://www.amazon.com/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard&pd_rd_r=89c237af-e7f2-4af6-b9c4&pd_rd_w=0aaaD&pd_rd_wg=KZWal&pf_rd_p=112312321&pf_rd_r=1233&qid=234231231&qu=eyJxc2MiOinFzcCI6IjYuMjAifQ%3D%3D&sr=8-1"),
+    );
+    assert_eq!(
+      "https://www.amazon.co.jp/dp/B00NLZUM36/",
+      replace_amazon("https://www.amazon.co.jp/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard&pd_rd_r=89c237af-e7f2-4af6-b9c4&pd_rd_w=0aaaD&pd_rd_wg=KZWal&pf_rd_p=112312321&pf_rd_r=1233&qid=234231231&qu=eyJxc2MiOinFzcCI6IjYuMjAifQ%3D%3D&sr=8-1"),
+    );
+  }
+
+  #[test]
+  fn amazon_search() {
+    assert_eq!(
+      "https://www.amazon.com/s?k=%E4%BD%A0%E5%A5%BD%26+%2B",
+      replace_amazon_search("https://www.amazon.com/s?k=%E4%BD%A0%E5%A5%BD%26+%2B&crid=1SHSKHE0RZCED&sprefix=%E4%BD%A0%E5%A5%BD%26+%2B%2Caps%2C1307&ref=nb_sb_noss_2")
+    )
+  }
+
+  #[test]
+  fn replace_barticle_test() {
+    assert_eq!(
+      "https://www.bilibili.com/read/cv19172625",
+      replace_barticle("https://www.bilibili.com/read/mobile/19172625?xxx=114514&asdfasdf=32394239ADSAD-12312aASDASD")
+    )
+  }
+
+  #[test]
+  fn replace_bfestival_test() {
+    assert_eq!(
+      "https://www.bilibili.com/video/BV1Xx4y1o7Ab",
+      replace_bfestival("https://www.bilibili.com/festival/2024bnj?bvid=BV1Xx4y1o7Ab&spm_id_from=333.1007.0.0&vd_source=0123456789abcdef")
+    );
+    let no_bvid = "https://www.bilibili.com/festival/2024bnj?spm_id_from=333.1007.0.0";
+    assert_eq!(no_bvid, replace_bfestival(no_bvid));
+  }
+
+  #[test]
+  fn replace_blive_test() {
+    assert_eq!(
+      "https://live.bilibili.com/12345",
+      replace_blive("https://live.bilibili.com/12345?broadcast_type=0&is_room_feed=1&spm_id_from=333.999.live_users_card.0.click")
+    );
+    assert_eq!(
+      "see https://live.bilibili.com/12345",
+      replace_blive("see live.bilibili.com/h5/12345/")
+    );
+  }
+
+  #[test]
+  fn replace_bbangumi_test() {
+    assert_eq!(
+      "https://www.bilibili.com/bangumi/play/ep123456",
+      replace_bbangumi("https://www.bilibili.com/bangumi/play/ep123456?from_spmid=666.25.episode.0&spm_id_from=333.1007.top_right_bar_window_history.content.click")
+    );
+    assert_eq!(
+      "https://www.bilibili.com/bangumi/play/ss41410",
+      replace_bbangumi("https://m.bilibili.com/bangumi/play/ss41410/?share_source=copy_web")
+    );
+  }
+
+  #[test]
+  fn replace_bcheese_test() {
+    assert_eq!(
+      "https://www.bilibili.com/cheese/play/ep123",
+      replace_bcheese("https://www.bilibili.com/cheese/play/ep123?csource=private_space_class_null&spm_id_from=333.999.0.0")
+    );
+    assert_eq!(
+      "course https://www.bilibili.com/cheese/play/ss4567",
+      replace_bcheese(
+        "course m.bilibili.com/cheese/play/ss4567/?csource=share&share_source=copy_web"
+      )
+    );
+  }
+
+  #[test]
+  fn replace_bspace_test() {
+    assert_eq!(
+      "https://space.bilibili.com/12345",
+      replace_bspace("https://space.bilibili.com/12345?spm_id_from=333.788.0.0")
+    );
+    assert_eq!(
+      "up: https://space.bilibili.com/12345/video",
+      replace_bspace(
+        "up: space.bilibili.com/12345/video?tid=0&pn=2&spm_id_from=333.1007.tianma.1-1-1.click"
+      )
+    );
+  }
+
+  #[test]
+  fn replace_bdynamic_test() {
+    assert_eq!(
+      "https://t.bilibili.com/987654321012345678",
+      replace_bdynamic(
+        "https://t.bilibili.com/987654321012345678?tab=2&share_source=COPY&share_medium=iphone"
+      )
+    );
+    assert_eq!(
+      "https://t.bilibili.com/987654321012345678",
+      replace_bdynamic("https://m.bilibili.com/dynamic/987654321012345678?share_source=weixin")
+    );
+  }
+
+  #[tokio::test]
+  async fn opus_not_reprocessed_as_dynamic() {
+    let outcome = replace_all(
+      "https://www.bilibili.com/opus/987654321012345678?spm_id_from=333.1365.0.0",
+      &HashSet::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!("https://t.bilibili.com/987654321012345678", outcome.text);
+    assert_eq!(1, outcome.replacements.len());
+
+    let outcome = replace_all("https://t.bilibili.com/987654321012345678", &HashSet::new())
+      .await
+      .unwrap();
+    assert!(!outcome.changed);
+  }
+
+  #[tokio::test]
+  async fn bshort_to_live_room() {
+    let resolver = mock(
+      "https://live.bilibili.com/12345?broadcast_type=0&is_room_feed=1&spm_id_from=333.999.live_users_card.0.click",
+    );
+    let outcome = replace_all_with_resolver("https://b23.tv/abcdEFG", &HashSet::new(), &resolver)
+      .await
+      .unwrap();
+    assert_eq!("https://live.bilibili.com/12345", outcome.text);
+  }
+
+  #[tokio::test]
+  async fn replace_xhslink_test() {
+    let resolver = mock(
+      "https://www.xiaohongshu.com/discovery/item/66a1b2c3000000000d00e4f5?app_platform=android&share_from_user_hidden=true&xsec_token=CBabc123&xsec_source=app_share",
+    );
+    let text = "看看这篇笔记 http://xhslink.com/a/AbCdEf 复制后打开【小红书】";
+    assert_eq!(
+      "看看这篇笔记 https://www.xiaohongshu.com/discovery/item/66a1b2c3000000000d00e4f5 复制后打开【小红书】",
+      replace_xhslink(text, &resolver, false).await.unwrap()
+    );
+    assert_eq!(
+      "看看这篇笔记 https://www.xiaohongshu.com/discovery/item/66a1b2c3000000000d00e4f5?xsec_token=CBabc123 复制后打开【小红书】",
+      replace_xhslink(text, &resolver, true).await.unwrap()
+    );
+
+    let login = mock("https://www.xiaohongshu.com/login?redirectPath=%2Fexplore");
+    assert_eq!(text, replace_xhslink(text, &login, false).await.unwrap());
+  }
+
+  #[test]
+  fn replace_twitter_test() {
+    assert_eq!(
+      "https://c.vxtwitter.com/Penny_0571/status/1587323246506528769",
+      replace_twitter(
+        "https://fixupx.com/Penny_0571/status/1587323246506528769?s=20&t=0Mzx3uLKTD-kygDQmaXvFq"
+      )
+    )
+  }
+
+  #[test]
+  fn replace_weixin_test() {
+    let text = "https://mp.weixin.qq.com/s?__biz=MzIzzMwNjc1NzU==&mid=2650309&idx=114514&sn=2fd9d2a3b0b544a6da&chksm=e8de3b77dfa9b2612b676b21f34a75a79994bfcd4a4#rd";
+    assert_eq!(
+      "https://mp.weixin.qq.com/s?__biz=MzIzzMwNjc1NzU%3D%3D&mid=2650309&idx=114514&sn=2fd9d2a3b0b544a6da#rd",
+      replace_weixin(
+        text
+      )
+    )
+  }
+
+  #[test]
+  fn replace_jd_test() {
+    assert_eq!(
+      "https://item.m.jd.com/product/100026923531.html",
+      replace_jd("https://item.m.jd.com/product/100026923531.html?&utm_source=iosapp&utm_medium=appshare&utm_campaign=114514&utm_term=CopyURL&ad_od=share&gx=T2nEPztRx6NTRa30RpDCM")
+    )
+  }
+
+  #[test]
+  fn replace_instagram_test() {
+    assert_eq!(
+      "https://www.instagram.com/reel/Cabc123/",
+      replace_instagram("https://www.instagram.com/reel/Cabc123/?igsh=MWQ1ZGUxMzBkMA%3D%3D&utm_source=ig_web_copy_link")
+    );
+    assert_eq!(
+      "https://www.instagram.com/p/C_x-Yz9/",
+      replace_instagram("https://instagr.am/p/C_x-Yz9/?utm_source=ig_share_sheet&igshid=1a2b3c")
+    );
+  }
+
+  #[test]
+  fn replace_reddit_test() {
+    assert_eq!(
+      "https://www.reddit.com/r/rust/comments/1abcde2/announcing_rust_1750/",
+      replace_reddit("https://www.reddit.com/r/rust/comments/1abcde2/announcing_rust_1750/?share_id=Xy1z2AbCdEfGh&utm_content=2&utm_medium=android_app&utm_name=androidcss&utm_source=share&utm_term=1")
+    );
+  }
+
+  #[test]
+  fn reddit_share_regex_test() {
+    let text = "https://www.reddit.com/r/rust/s/aB3xYz?utm_source=share";
+    let found = REDDIT_SHARE_REGEX.find(text).unwrap().unwrap();
+    assert_eq!(text, found.as_str());
+    assert!(REDDIT_SHARE_REGEX
+      .find("https://www.reddit.com/r/rust/comments/1abcde2/")
+      .unwrap()
+      .is_none());
+  }
+
+  #[test]
+  fn replace_aliexpress_test() {
+    assert_eq!(
+      "https://www.aliexpress.com/item/1005006012345678.html",
+      replace_aliexpress("https://www.aliexpress.com/item/1005006012345678.html?spm=a2g0o.productlist.main.1.4b3c1a2bXyZabc&algo_pvid=9b1c2d3e&gatewayAdapt=glo2usa&sku_id=12000035512345678")
+    );
+    assert_eq!(
+      "https://www.aliexpress.com/item/1005006012345678.html",
+      replace_aliexpress(
+        "https://de.aliexpress.com/item/1005006012345678.html?gatewayAdapt=glo2deu"
+      )
+    );
+    assert_eq!(
+      "https://www.aliexpress.com/item/1005006012345678.html",
+      replace_aliexpress(
+        "https://aliexpress.us/item/1005006012345678.html?gatewayAdapt=glo2usa4itemAdapt"
+      )
+    );
+  }
+
+  #[test]
+  fn aliexpress_short_regex_test() {
+    let text = "https://a.aliexpress.com/_mKabc12";
+    let found = ALIEXPRESS_SHORT_REGEX.find(text).unwrap().unwrap();
+    assert_eq!(text, found.as_str());
+    assert!(ALIEXPRESS_REGEX.find(text).unwrap().is_none());
+  }
+
+  #[test]
+  fn replace_taobao_test() {
+    let mut text = "https://item.taobao.com/item.htm?spm=a1z10.1-c.w4004-123.2.5b3c&id=712345678901&scm=1007.40986.123.0&pvid=abc".to_string();
+    replace_taobao(&mut text);
+    assert_eq!("https://item.taobao.com/item.htm?id=712345678901", text);
+
+    let mut text =
+      "https://detail.tmall.com/item.htm?id=623456789012&skuId=5012345&spm=a21n57.1.0.0"
+        .to_string();
+    replace_taobao(&mut text);
+    assert_eq!("https://detail.tmall.com/item.htm?id=623456789012", text);
+  }
+
+  #[test]
+  fn taobao_login_wall() {
+    let login = Url::from_str("https://login.m.taobao.com/login.htm?redirectURL=https%3A%2F%2Fitem.taobao.com%2Fitem.htm%3Fid%3D1").unwrap();
+    assert!(is_taobao_login_wall(&login));
+    let item = Url::from_str("https://item.taobao.com/item.htm?id=712345678901").unwrap();
+    assert!(!is_taobao_login_wall(&item));
+  }
+
+  #[tokio::test]
+  async fn replace_all_skips_disabled() {
+    let text = "https://www.amazon.com/Redragon-S101/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard https://item.m.jd.com/product/100026923531.html?utm_source=iosapp";
+    let disabled = HashSet::from([Platform::Amazon]);
+    assert_eq!(
+      "https://www.amazon.com/Redragon-S101/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard https://item.m.jd.com/product/100026923531.html",
+      replace_all(text, &disabled).await.unwrap().text
+    );
+  }
+
+  #[tokio::test]
+  async fn replace_all_records_replacements() {
+    let text =
+      "see https://item.m.jd.com/product/100026923531.html?utm_source=iosapp&utm_medium=appshare";
+    let outcome = replace_all(text, &HashSet::new()).await.unwrap();
+    assert!(outcome.changed);
+    assert_eq!(
+      "see https://item.m.jd.com/product/100026923531.html",
+      outcome.text
+    );
+    assert_eq!(
+      vec![Replacement {
+        platform: Platform::Jd,
+        original:
+          "https://item.m.jd.com/product/100026923531.html?utm_source=iosapp&utm_medium=appshare"
+            .to_string(),
+        replaced: "https://item.m.jd.com/product/100026923531.html".to_string()
+      }],
+      outcome.replacements
+    );
+
+    let outcome = replace_all("nothing to see", &HashSet::new())
+      .await
+      .unwrap();
+    assert!(!outcome.changed);
+    assert!(outcome.replacements.is_empty());
+  }
+
+  /// Redirects every url to `target`, counting how often it was asked.
+  struct MockResolver {
+    target: &'static str,
+    calls: std::sync::atomic::AtomicUsize,
+  }
+
+  #[async_trait]
+  impl RedirectResolver for MockResolver {
+    async fn resolve(&self, _url: &str) -> Result<Url> {
+      self
+        .calls
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+      Ok(Url::from_str(self.target)?)
+    }
+  }
+
+  /// Like [`MockResolver`], but lets other resolves run before answering.
+  struct YieldingResolver(MockResolver);
+
+  #[async_trait]
+  impl RedirectResolver for YieldingResolver {
+    async fn resolve(&self, url: &str) -> Result<Url> {
+      let resolved = self.0.resolve(url).await;
+      tokio::task::yield_now().await;
+      resolved
+    }
+  }
+
+  /// Redirects the urls in `hops` to their targets, and anything else to itself.
+  struct ChainResolver {
+    hops: HashMap<&'static str, &'static str>,
+    calls: std::sync::atomic::AtomicUsize,
+  }
+
+  #[async_trait]
+  impl RedirectResolver for ChainResolver {
+    async fn resolve(&self, url: &str) -> Result<Url> {
+      self
+        .calls
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+      Ok(Url::from_str(self.hops.get(url).copied().unwrap_or(url))?)
+    }
+  }
+
+  #[test]
+  fn custom_rule_rewrites_url() {
+    let rule: CustomRule = toml::from_str(
+      r#"
+pattern = 'https://m\.example\.com/item/(?P<id>[0-9]+)\S*'
+replacement = "https://www.example.com/item/$id"
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+      "https://www.example.com/item/42",
+      rule.replace("https://m.example.com/item/42?from=share&uid=7")
+    );
+
+    let rule: CustomRule = toml::from_str(
+      r#"
+pattern = 'https://news\.example\.com/\S*'
+replacement = "$0"
+keep-params = ["id"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+      "https://news.example.com/article?id=1",
+      rule.replace("https://news.example.com/article?id=1&utm_source=x")
+    );
+
+    assert!(toml::from_str::<CustomRule>("pattern = '(unclosed'\nreplacement = ''").is_err());
+  }
+
+  #[tokio::test]
+  async fn detect_affiliate_params() {
+    let outcome = replace_all(
+      "https://www.amazon.com/Some-Product/dp/B0C1234567/?tag=poster-20&ascsubtag=abc&th=1",
+      &HashSet::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!("https://www.amazon.com/dp/B0C1234567/", outcome.text);
+    assert_eq!(vec!["tag", "ascsubtag"], outcome.affiliate_params);
+
+    let outcome = replace_all(
+      "https://www.amazon.com/Some-Product/dp/B0C1234567/?th=1",
+      &HashSet::new(),
+    )
+    .await
+    .unwrap();
+    assert!(outcome.changed);
+    assert!(outcome.affiliate_params.is_empty());
+  }
+
+  #[tokio::test]
+  async fn resolve_chained_short_links() {
+    let resolver = ChainResolver {
+      hops: HashMap::from([
+        ("https://t.co/jqpeEFD8Nz", "https://b23.tv/lBI8Ov3"),
+        (
+          "https://b23.tv/lBI8Ov3",
+          "https://www.bilibili.com/video/BV1se4y177g9/?share_source=copy_web",
+        ),
+      ]),
+      calls: Default::default(),
+    };
+    let outcome = replace_all_with_resolver("https://t.co/jqpeEFD8Nz", &HashSet::new(), &resolver)
+      .await
+      .unwrap();
+    assert_eq!("https://www.bilibili.com/video/BV1se4y177g9/", outcome.text);
+    assert_eq!(2, outcome.replacements.len());
+  }
+
+  /// Resolves like [`ChainResolver`] once `barrier` is reached by as many resolves at once.
+  struct BarrierResolver {
+    hops: HashMap<&'static str, &'static str>,
+    barrier: tokio::sync::Barrier,
+  }
+
+  #[async_trait]
+  impl RedirectResolver for BarrierResolver {
+    async fn resolve(&self, url: &str) -> Result<Url> {
+      self.barrier.wait().await;
+      Ok(Url::from_str(self.hops.get(url).copied().unwrap_or(url))?)
+    }
+  }
+
+  /// Never resolves anything in time.
+  struct SlowResolver;
+
+  #[async_trait]
+  impl RedirectResolver for SlowResolver {
+    async fn resolve(&self, url: &str) -> Result<Url> {
+      tokio::time::sleep(Duration::from_secs(60)).await;
+      Ok(Url::from_str(url)?)
+    }
+  }
+
+  #[tokio::test]
+  async fn skip_short_links_past_deadline() {
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(50);
+    let outcome = tokio::time::timeout(
+      Duration::from_secs(5),
+      replace_all_until(
+        "https://b23.tv/lBI8Ov3 and https://www.bilibili.com/video/BV114514/?t=1&spm=1.2",
+        &HashSet::new(),
+        &SlowResolver,
+        Some(deadline),
+      ),
+    )
+    .await
+    .expect("deadline was ignored")
+    .unwrap();
+    assert_eq!(
+      "https://b23.tv/lBI8Ov3 and https://www.bilibili.com/video/BV114514/?t=1",
+      outcome.text
+    );
+  }
+
+  #[tokio::test]
+  async fn resolve_short_links_concurrently() {
+    let resolver = BarrierResolver {
+      hops: HashMap::from([
+        (
+          "https://b23.tv/lBI8Ov3",
+          "https://www.bilibili.com/video/BV1se4y177g9/?share_source=copy_web",
+        ),
+        (
+          "https://amzn.to/3xYzAbC",
+          "https://www.amazon.com/Redragon-S101-Keyboard/dp/B00NLZUM36/?_encoding=UTF8&pd_rd_w=0aaaD",
+        ),
+      ]),
+      // Only reached when both links are resolved at the same time.
+      barrier: tokio::sync::Barrier::new(2),
+    };
+    let outcome = tokio::time::timeout(
+      Duration::from_secs(5),
+      replace_all_with_resolver(
+        "https://amzn.to/3xYzAbC and https://b23.tv/lBI8Ov3",
+        &HashSet::new(),
+        &resolver,
+      ),
+    )
+    .await
+    .expect("short links were resolved one by one")
+    .unwrap();
+    assert_eq!(
+      "https://www.amazon.com/dp/B00NLZUM36/ and https://www.bilibili.com/video/BV1se4y177g9/",
+      outcome.text
+    );
+    assert_eq!(
+      vec![Platform::Bilibili, Platform::Amazon],
+      outcome
+        .replacements
+        .iter()
+        .map(|rep| rep.platform)
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[tokio::test]
+  async fn redirect_loop_is_bounded() {
+    let resolver = ChainResolver {
+      hops: HashMap::from([
+        ("https://t.co/aaaa", "https://t.co/bbbb"),
+        ("https://t.co/bbbb", "https://t.co/aaaa"),
+      ]),
+      calls: Default::default(),
+    };
+    let outcome = replace_all_with_resolver("https://t.co/aaaa", &HashSet::new(), &resolver)
+      .await
+      .unwrap();
+    assert_eq!("https://t.co/aaaa", outcome.text);
+    assert_eq!(2, resolver.calls.load(std::sync::atomic::Ordering::Relaxed));
+  }
+
+  #[tokio::test]
+  async fn resolve_repeated_short_link_once() {
+    // Yielding lets the second range start resolving before the first one is done.
+    let resolver = YieldingResolver(MockResolver {
+      target: "https://www.bilibili.com/video/BV1se4y177g9/?share_source=copy_web",
+      calls: Default::default(),
+    });
+    let outcome = replace_all_with_resolver(
+      "https://b23.tv/lBI8Ov3\n> https://b23.tv/lBI8Ov3",
+      &HashSet::new(),
+      &resolver,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+      "https://www.bilibili.com/video/BV1se4y177g9/\n> https://www.bilibili.com/video/BV1se4y177g9/",
+      outcome.text
+    );
+    assert_eq!(1, resolver.0.calls.into_inner());
+  }
+
+  fn mock(target: &'static str) -> MockResolver {
+    MockResolver {
+      target,
+      calls: Default::default(),
+    }
+  }
+
+  #[tokio::test]
+  async fn cached_short_link_skips_resolver() {
+    let resolver = CachedResolver::new(
+      mock("https://www.bilibili.com/video/BV1se4y177g9/?share_source=copy_web"),
+      Duration::from_secs(60),
+      16,
+    );
+    for _ in 0..2 {
+      let outcome = replace_all_with_resolver("https://b23.tv/lBI8Ov3", &HashSet::new(), &resolver)
+        .await
+        .unwrap();
+      assert_eq!("https://www.bilibili.com/video/BV1se4y177g9/", outcome.text);
+    }
+    assert_eq!(1, resolver.inner.calls.into_inner());
+  }
+
+  #[tokio::test]
+  async fn cache_expires_and_evicts() {
+    let expiring = CachedResolver::new(mock("https://example.com/"), Duration::ZERO, 16);
+    expiring.resolve("https://b23.tv/a").await.unwrap();
+    expiring.resolve("https://b23.tv/a").await.unwrap();
+    assert_eq!(2, expiring.inner.calls.into_inner());
+
+    let bounded = CachedResolver::new(mock("https://example.com/"), Duration::from_secs(60), 2);
+    for url in ["https://b23.tv/a", "https://b23.tv/b", "https://b23.tv/c"] {
+      bounded.resolve(url).await.unwrap();
+    }
+    assert_eq!(2, bounded.resolved.lock().unwrap().len());
+    bounded.resolve("https://b23.tv/a").await.unwrap();
+    assert_eq!(4, bounded.inner.calls.into_inner());
+  }
+
+  #[test]
+  fn replace_spotify_test() {
+    assert_eq!(
+      "https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT",
+      replace_spotify("https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT?si=1a2b3c4d5e6f4a7b&utm_source=copy-link")
+    );
+    assert_eq!(
+      "https://open.spotify.com/playlist/37i9dQZF1DXcBWIGoYBM5M",
+      replace_spotify(
+        "https://open.spotify.com/playlist/37i9dQZF1DXcBWIGoYBM5M?si=9f8e7d6c5b4a4321&pi=a-AbCdEf"
+      )
+    );
+  }
+
+  #[test]
+  fn strip_common_trackers_test() {
+    let options = ReplaceOptions::default();
+    assert_eq!(
+      "https://blog.example.org/posts/42?page=2#comments",
+      strip_common_trackers(
+        "https://blog.example.org/posts/42?utm_source=newsletter&page=2&gclid=EAIaIQobChMI&utm_campaign=spring#comments",
+        &options
+      )
+    );
+    let clean = "https://blog.example.org/posts/42?page=2";
+    assert_eq!(clean, strip_common_trackers(clean, &options));
+
+    let options = ReplaceOptions {
+      extra_trackers: vec!["ref".to_string()],
+      ..Default::default()
+    };
+    assert_eq!(
+      "https://blog.example.org/posts/42",
+      strip_common_trackers("https://blog.example.org/posts/42?ref=hn", &options)
+    );
+  }
+
+  #[test]
+  fn blocklisted_domain_untouched() {
+    let options = ReplaceOptions {
+      domain_blocklist: vec!["example.org".to_string()],
+      ..Default::default()
+    };
+    let internal = "https://wiki.example.org/page?utm_source=chat";
+    assert_eq!(internal, strip_common_trackers(internal, &options));
+    assert_eq!(
+      "https://notexample.org/page",
+      strip_common_trackers("https://notexample.org/page?utm_source=chat", &options)
+    );
+    assert!(is_in_domains("example.org/page", &options.domain_blocklist));
+  }
+
+  #[test]
+  fn fragment_trackers() {
+    let options = ReplaceOptions {
+      fragment_tracker_domains: vec!["example.com".to_string()],
+      ..Default::default()
+    };
+    assert_eq!(
+      "https://app.example.com/share#id=1",
+      strip_common_trackers(
+        "https://app.example.com/share#utm_source=chat&id=1",
+        &options
+      )
+    );
+    assert_eq!(
+      "https://example.com/#/item/1?ref=home",
+      strip_common_trackers(
+        "https://example.com/#/item/1?utm_medium=share&ref=home",
+        &options
+      )
+    );
+    assert_eq!(
+      "https://example.com/#/item/1",
+      strip_common_trackers("https://example.com/#/item/1?fbclid=abc", &options)
+    );
+    let anchor = "https://example.com/docs?utm_source=chat#section-2";
+    assert_eq!(
+      "https://example.com/docs#section-2",
+      strip_common_trackers(anchor, &options)
+    );
+    let elsewhere = "https://example.org/share#utm_source=chat";
+    assert_eq!(elsewhere, strip_common_trackers(elsewhere, &options));
+  }
+
+  #[test]
+  fn redirect_client_with_proxy() {
+    let options = ClientOptions {
+      proxy: Some("http://127.0.0.1:7899".to_string()),
+      ..Default::default()
+    };
+    assert!(build_redirect_client(&options).is_ok());
+    let options = ClientOptions {
+      proxy: Some("not a proxy".to_string()),
+      ..Default::default()
+    };
+    assert!(build_redirect_client(&options).is_err());
+  }
+
+  #[tokio::test]
+  async fn redirect_hops_per_host() {
+    use axum::{
+      http::{header::LOCATION, StatusCode},
+      routing::get,
+      Router,
+    };
+
+    let app = Router::new()
+      .route(
+        "/short",
+        get(|| async { (StatusCode::FOUND, [(LOCATION, "/bounce")]) }),
+      )
+      .route(
+        "/bounce",
+        get(|| async { (StatusCode::FOUND, [(LOCATION, "/target")]) }),
+      )
+      .route("/target", get(|| async { "target" }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let short = format!("http://{addr}/short");
+
+    let client = build_redirect_client(&ClientOptions::default()).unwrap();
+    let resolved = resolve_with(&client, &short).await.unwrap();
+    assert_eq!("/bounce", resolved.path());
+
+    let options = ClientOptions {
+      redirect_hops: HashMap::from([("127.0.0.1".to_string(), 2)]),
+      ..Default::default()
+    };
+    let client = build_redirect_client(&options).unwrap();
+    let resolved = resolve_with(&client, &short).await.unwrap();
+    assert_eq!("/target", resolved.path());
+  }
+
+  #[test]
+  fn replace_youtube_test() {
+    let replace = |url: &str| {
+      let mut text = url.to_string();
+      replace_youtube(&mut text);
+      text
+    };
+    assert_eq!(
+      "https://www.youtube.com/watch?v=aBcD_1234-x",
+      replace("https://youtube.com/shorts/aBcD_1234-x?si=Xyz123abc&feature=share")
+    );
+    assert_eq!(
+      "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=42",
+      replace("https://youtu.be/dQw4w9WgXcQ?si=Xyz123abc&t=42")
+    );
+    assert_eq!(
+      "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123",
+      replace("https://m.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123&pp=ygU")
+    );
+    assert_eq!(
+      "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+      replace("https://music.youtube.com/watch?v=dQw4w9WgXcQ&feature=share")
+    );
   }
-  Ok(new_str)
-}
 
-fn replace_barticle(str: &str) -> String {
-  BARTICLE_REGEX
-    .replace_all(str, "https://www.bilibili.com/read/cv$cvid")
-    .into()
-}
+  #[test]
+  fn configured_keep_params() {
+    let options = ReplaceOptions {
+      keep_params: HashMap::from([(Platform::Youtube, KeepParams::Keys(vec!["v".to_string()]))]),
+      ..Default::default()
+    };
+    let mut url =
+      Url::from_str("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123&t=42&si=abc").unwrap();
+    trim_youtube_link(&mut url, &options);
+    assert_eq!("https://www.youtube.com/watch?v=dQw4w9WgXcQ", url.as_str());
 
-fn replace_bopus(str: &str) -> String {
-  BOPUS_REGEX
-    .replace_all(str, "https://t.bilibili.com/$opus_id")
-    .into()
-}
+    let mut url =
+      Url::from_str("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123&t=42&si=abc").unwrap();
+    trim_youtube_link(&mut url, &ReplaceOptions::default());
+    assert_eq!(
+      "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123&t=42",
+      url.as_str()
+    );
+  }
 
-async fn get_redirect_url(url: &str) -> Result<Url> {
-  let resp = CLIENT_REDIRECT_ONCE
-    .get(url)
-    .send()
-    .await
-    .with_context(|| format!("Failed to get url {url}"))?;
-  Ok(resp.url().clone())
-}
+  #[test]
+  fn replace_github_test() {
+    assert_eq!(
+      "https://github.com/Colerar/fuckburl-bot?tab=readme-ov-file",
+      replace_github("https://github.com/Colerar/fuckburl-bot?tab=readme-ov-file&utm_source=newsletter&utm_medium=email&utm_campaign=weekly")
+    );
+    assert_eq!(
+      "https://github.com/Colerar/fuckburl-bot/pull/1",
+      replace_github(
+        "https://github.com/Colerar/fuckburl-bot/pull/1?notification_referrer_id=NT_kwDOAbCdEf"
+      )
+    );
+    assert_eq!(
+      "https://github.com/Colerar/fuckburl-bot/blob/master/src/main.rs?plain=1#L10",
+      replace_github(
+        "https://github.com/Colerar/fuckburl-bot/blob/master/src/main.rs?plain=1&utm_source=x#L10"
+      )
+    );
+    assert_eq!(
+      "see https://github.com/Colerar/fuckburl-bot?tab=readme-ov-file",
+      replace_github("see github.com/Colerar/fuckburl-bot?tab=readme-ov-file&utm_source=x")
+    );
+    let gist = "gist.github.com/Colerar/abc?utm_source=x";
+    assert_eq!(gist, replace_github(gist));
+  }
 
-trait RemovePairsIf {
-  fn remove_pairs_if_key<P>(&mut self, predicate: P)
-  where
-    Self: Sized,
-    P: Fn(&str) -> bool;
+  #[test]
+  fn replace_twitch_test() {
+    assert_eq!(
+      "https://clips.twitch.tv/AwkwardHelplessSalamanderSwiftRage-1a2B3c",
+      replace_twitch("https://clips.twitch.tv/AwkwardHelplessSalamanderSwiftRage-1a2B3c?tt_content=url&tt_medium=clips_api")
+    );
+    assert_eq!(
+      "https://www.twitch.tv/videos/2012345678?t=1h2m3s",
+      replace_twitch("https://m.twitch.tv/videos/2012345678?filter=archives&sort=time&t=1h2m3s&tt_medium=mobile_web_share")
+    );
+    assert_eq!(
+      "https://www.twitch.tv/some_streamer",
+      replace_twitch("https://m.twitch.tv/some_streamer")
+    );
+    let clean = "https://www.twitch.tv/some_streamer";
+    assert_eq!(clean, replace_twitch(clean));
+  }
 
-  #[inline]
-  fn keep_pairs_only_in(&mut self, vec: Cow<[&str]>)
-  where
-    Self: Sized,
-  {
-    self.remove_pairs_if_key(|k| !vec.contains(&k));
+  #[test]
+  fn replace_steam_test() {
+    assert_eq!(
+      "https://store.steampowered.com/app/440/Team_Fortress_2/",
+      replace_steam("https://store.steampowered.com/app/440/Team_Fortress_2/?snr=1_4_4__tab-TopSellers&utm_source=twitter&utm_campaign=summer")
+    );
+    assert_eq!(
+      "https://store.steampowered.com/app/440",
+      replace_steam("store.steampowered.com/app/440?utm_source=discord")
+    );
+    assert_eq!(
+      "https://steamcommunity.com/market/listings/730/AK-47%20%7C%20Redline%20%28Field-Tested%29",
+      replace_steam_market("https://steamcommunity.com/market/listings/730/AK-47%20%7C%20Redline%20%28Field-Tested%29?snr=1_5_9__search&utm_medium=share")
+    );
   }
-}
 
-impl RemovePairsIf for Url {
-  #[inline]
-  fn remove_pairs_if_key<P>(&mut self, predicate: P)
-  where
-    Self: Sized,
-    P: Fn(&str) -> bool,
-  {
-    let buf = String::new();
-    let mut ser = form_urlencoded::Serializer::new(buf);
-    self.query_pairs().into_iter().for_each(|(k, v)| {
-      if !predicate(k.borrow()) {
-        ser.append_pair(k.borrow(), v.borrow());
-      }
-    });
+  #[test]
+  fn replace_discord_test() {
+    assert_eq!(
+      "https://discord.gg/rust-lang",
+      replace_discord("https://discord.gg/rust-lang?event=1234567890123456789")
+    );
+    assert_eq!(
+      "https://discord.com/channels/273534239310479360/273541522815713281/1201234567890123456",
+      replace_discord("https://discord.com/channels/273534239310479360/273541522815713281/1201234567890123456?utm_source=share")
+    );
+    let clean = "https://discord.com/invite/rust-lang";
+    assert_eq!(clean, replace_discord(clean));
+  }
 
-    self.set_query(match &*ser.finish() {
-      "" => None,
-      query => Some(query),
-    });
+  #[test]
+  fn replace_epic_test() {
+    assert_eq!(
+      "https://store.epicgames.com/p/hades",
+      replace_epic(
+        "https://store.epicgames.com/en-US/p/hades?epic_affiliate=someone&epic_gameId=abc123"
+      )
+    );
+    assert_eq!(
+      "https://store.epicgames.com/p/fortnite",
+      replace_epic("store.epicgames.com/p/fortnite?lang=en-US")
+    );
   }
-}
 
-#[cfg(test)]
-mod tests {
-  use super::*;
+  #[test]
+  fn replace_gog_test() {
+    assert_eq!(
+      "https://www.gog.com/game/the_witcher_3_wild_hunt_game_of_the_year_edition",
+      replace_gog("https://www.gog.com/en/game/the_witcher_3_wild_hunt_game_of_the_year_edition?utm_source=twitter&pp=abc")
+    );
+    assert_eq!(
+      "https://www.gog.com/game/cyberpunk_2077",
+      replace_gog("gog.com/game/cyberpunk_2077?as=1630110786")
+    );
+  }
+
+  #[test]
+  fn replace_facebook_test() {
+    assert_eq!(
+      "https://www.facebook.com/zuck/posts/10114123456789012",
+      replace_facebook("https://m.facebook.com/zuck/posts/10114123456789012?fbclid=IwAR2abcDEF_ghi&mibextid=Nif5oz")
+    );
+    assert_eq!(
+      "https://www.facebook.com/watch/?v=1234567890123456",
+      replace_facebook(
+        "https://www.facebook.com/watch/?v=1234567890123456&ref=sharing&fbclid=IwAR0xyz"
+      )
+    );
+  }
 
   #[tokio::test]
-  async fn remove_all() {
-    let mut text = "https://www.bilibili.com/video/BV1Hg411T7fT/?spm_id_from=333.788.recommend_more_video.1&vd_source=425ad7d352481d80617a03327da07da0".to_string();
-    replace_btrack(&mut text);
-    assert_eq!("https://www.bilibili.com/video/BV1Hg411T7fT/", text);
+  async fn replace_fbwatch_share_test() {
+    let resolver = MockResolver {
+      target: "https://m.facebook.com/watch/?v=1234567890123456&ref=sharing&rdid=AbCdEf",
+      calls: Default::default(),
+    };
+    assert_eq!(
+      "https://www.facebook.com/watch/?v=1234567890123456",
+      replace_fbwatch_share("https://fb.watch/aBcD-12_3/", &resolver)
+        .await
+        .unwrap()
+    );
   }
 
   #[test]
-  fn keep_certain_params() {
-    {
-      let mut text =
-        "https://www.bilibili.com/video/BV114514/?t=123&p=1&spm=1.2212.22321".to_string();
-      replace_btrack(&mut text);
-      assert_eq!("https://www.bilibili.com/video/BV114514/?t=123&p=1", text);
-    }
-    {
-      let mut text = "https://www.bilibili.com/video/BV114514/?t=123&spm=1.2212.22321".to_string();
-      replace_btrack(&mut text);
-      assert_eq!("https://www.bilibili.com/video/BV114514/?t=123", text);
-    }
+  fn replace_pixiv_test() {
+    assert_eq!(
+      "https://www.pixiv.net/artworks/12345678",
+      replace_pixiv("https://www.pixiv.net/artworks/12345678?utm_source=twitter&utm_medium=share")
+    );
+    assert_eq!(
+      "https://www.pixiv.net/artworks/12345678",
+      replace_pixiv("https://www.pixiv.net/en/artworks/12345678")
+    );
+    assert_eq!(
+      "https://www.pixiv.net/artworks/12345678",
+      replace_pixiv("https://pixiv.net/ja/artworks/12345678?p=0")
+    );
   }
 
   #[tokio::test]
-  async fn bshort() {
-    let text = "https://b23.tv/lBI8Ov3".to_string();
-    let result = replace_bshort(&text).await.unwrap();
-    assert_eq!("https://www.bilibili.com/video/BV1se4y177g9/?t=100", result);
+  async fn replace_twitter_short_test() {
+    let text = "https://t.co/jqpeEFD8Nz".to_string();
+    let result = replace_twitter_short(&text, &mock("https://sdl.moe/"))
+      .await
+      .unwrap();
+    assert_eq!("https://sdl.moe/", result)
   }
 
-  #[test]
-  fn amazon() {
+  #[tokio::test]
+  async fn replace_amazon_short_test() {
+    let resolver = mock(
+      "https://www.amazon.com/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/?_encoding=UTF8&pd_rd_w=0aaaD&ref_=pd_gw_ci_mcx_mr_hp_d",
+    );
+    assert_eq!(
+      "look https://www.amazon.com/dp/B00NLZUM36/",
+      replace_amazon_short("look https://amzn.to/3xYzAbC", &resolver)
+        .await
+        .unwrap()
+    );
     assert_eq!(
       "https://www.amazon.com/dp/B00NLZUM36/",
-      replace_amazon("https://www.amazon.com/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard&pd_rd_r=89c237af-e7f2-4af6-b9c4&pd_rd_w=0aaaD&pd_rd_wg=KZWal&pf_rd_p=112312321&pf_rd_r=1233&qid=234231231&qu=eyJxc2MiOinFzcCI6IjYuMjAifQ%3D%3D&sr=8-1"),
+      replace_amazon_short("https://a.co/d/8kQzXyZ", &resolver)
+        .await
+        .unwrap()
+    );
+  }
+
+  #[test]
+  fn replace_nicovideo_test() {
+    assert_eq!(
+      "https://www.nicovideo.jp/watch/sm12345678",
+      replace_nicovideo("https://www.nicovideo.jp/watch/sm12345678?ref=twitter_ss&cp_in=wt_share")
     );
     assert_eq!(
-      "https://www.amazon.co.jp/dp/B00NLZUM36/",
-      replace_amazon("https://www.amazon.co.jp/Redragon-S101-Keyboard-Ergonomic-Programmable/dp/B00NLZUM36/ref=sr_1_1?keywords=gaming+keyboard&pd_rd_r=89c237af-e7f2-4af6-b9c4&pd_rd_w=0aaaD&pd_rd_wg=KZWal&pf_rd_p=112312321&pf_rd_r=1233&qid=234231231&qu=eyJxc2MiOinFzcCI6IjYuMjAifQ%3D%3D&sr=8-1"),
+      "look https://www.nicovideo.jp/watch/so40123456",
+      replace_nicovideo("look https://sp.nicovideo.jp/watch/so40123456")
     );
   }
 
   #[test]
-  fn amazon_search() {
+  fn replace_weibo_test() {
     assert_eq!(
-      "https://www.amazon.com/s?k=%E4%BD%A0%E5%A5%BD%26+%2B",
-      replace_amazon_search("https://www.amazon.com/s?k=%E4%BD%A0%E5%A5%BD%26+%2B&crid=1SHSKHE0RZCED&sprefix=%E4%BD%A0%E5%A5%BD%26+%2B%2Caps%2C1307&ref=nb_sb_noss_2")
-    )
+      "https://weibo.com/1234567890/NqXyZ1aBc",
+      replace_weibo(
+        "https://weibo.com/1234567890/NqXyZ1aBc?type=comment&weibo_id=4923456789012345"
+      )
+    );
+    assert_eq!(
+      "look https://weibo.com/status/4923456789012345",
+      replace_weibo("look https://m.weibo.cn/status/4923456789012345?wm=3333_2001&from=10E2193010&sourcetype=weixin")
+    );
+    let wrapped = "https://weibo.cn/sinaurl?u=https%3A%2F%2Fexample.com%2F";
+    assert_eq!(wrapped, replace_weibo(wrapped));
+  }
+
+  #[tokio::test]
+  async fn replace_tcn_short_test() {
+    let resolver =
+      mock("https://m.weibo.cn/detail/4923456789012345?sourcetype=weixin&utm_source=share");
+    assert_eq!(
+      "https://weibo.com/detail/4923456789012345",
+      replace_tcn_short("https://t.cn/A6abcDeF", &resolver)
+        .await
+        .unwrap()
+    );
+    let resolver = mock("https://example.com/article?id=1&utm_source=weibo");
+    assert_eq!(
+      "see https://example.com/article?id=1",
+      replace_tcn_short("see t.cn/A6abcDeF", &resolver)
+        .await
+        .unwrap()
+    );
   }
 
   #[test]
-  fn replace_barticle_test() {
+  fn replace_naver_test() {
     assert_eq!(
-      "https://www.bilibili.com/read/cv19172625",
-      replace_barticle("https://www.bilibili.com/read/mobile/19172625?xxx=114514&asdfasdf=32394239ADSAD-12312aASDASD")
-    )
+      "https://blog.naver.com/someblogger/223456789012",
+      replace_naver("https://blog.naver.com/someblogger/223456789012?trackingCode=rss")
+    );
+    assert_eq!(
+      "look https://blog.naver.com/someblogger/223456789012",
+      replace_naver(
+        "look https://m.blog.naver.com/someblogger/223456789012?referrerCode=1&isInf=true"
+      )
+    );
+    assert_eq!(
+      "https://cafe.naver.com/somecafe/1234",
+      replace_naver("https://m.cafe.naver.com/somecafe/1234?art=ZXh0ZXJuYWw&iframe_url_utf8=x")
+    );
+    let legacy = "https://blog.naver.com/PostView.naver?blogId=someblogger&logNo=223456789012";
+    assert_eq!(legacy, replace_naver(legacy));
+  }
+
+  #[tokio::test]
+  async fn replace_naverme_short_test() {
+    let resolver = mock("https://m.blog.naver.com/someblogger/223456789012?trackingCode=external");
+    assert_eq!(
+      "see https://blog.naver.com/someblogger/223456789012",
+      replace_naverme_short("see https://naver.me/xAbC12dE", &resolver)
+        .await
+        .unwrap()
+    );
+    let resolver = mock("https://example.com/article?id=1&utm_source=naver");
+    assert_eq!(
+      "https://example.com/article?id=1",
+      replace_naverme_short("naver.me/xAbC12dE", &resolver)
+        .await
+        .unwrap()
+    );
   }
 
   #[test]
-  fn replace_twitter_test() {
+  fn replace_apple_music_test() {
     assert_eq!(
-      "https://c.vxtwitter.com/Penny_0571/status/1587323246506528769",
-      replace_twitter(
-        "https://fixupx.com/Penny_0571/status/1587323246506528769?s=20&t=0Mzx3uLKTD-kygDQmaXvFq"
+      "https://music.apple.com/us/album/abbey-road-remastered/1441164426?i=1441164430",
+      replace_apple_music(
+        "https://music.apple.com/us/album/abbey-road-remastered/1441164426?i=1441164430&ls=1&uo=4"
       )
-    )
+    );
+    assert_eq!(
+      "https://music.apple.com/jp/album/abbey-road-remastered/1441164426",
+      replace_apple_music(
+        "https://music.apple.com/jp/album/abbey-road-remastered/1441164426?ls=1&app=music"
+      )
+    );
+    assert_eq!(
+      "https://podcasts.apple.com/us/podcast/the-daily/id1200361736?i=1000650000000",
+      replace_apple_music("https://podcasts.apple.com/us/podcast/the-daily/id1200361736?i=1000650000000&itsct=podcast_box&itscg=30200")
+    );
+    assert_eq!(
+      "https://music.apple.com/us/song/come-together/1441164430",
+      replace_apple_music("music.apple.com/us/song/come-together/1441164430?ls=1")
+    );
   }
 
   #[test]
-  fn replace_weixin_test() {
-    let text = "https://mp.weixin.qq.com/s?__biz=MzIzzMwNjc1NzU==&mid=2650309&idx=114514&sn=2fd9d2a3b0b544a6da&chksm=e8de3b77dfa9b2612b676b21f34a75a79994bfcd4a4#rd";
+  fn replace_qqdocs_test() {
     assert_eq!(
-      "https://mp.weixin.qq.com/s?__biz=MzIzzMwNjc1NzU%3D%3D&mid=2650309&idx=114514&sn=2fd9d2a3b0b544a6da#rd",
-      replace_weixin(
-        text
+      "https://docs.qq.com/doc/DV2ZxYWdNRUl6Q3Zx",
+      replace_qqdocs(
+        "https://docs.qq.com/doc/DV2ZxYWdNRUl6Q3Zx?u=0123456789abcdef&nlc=1&client_hint=0"
       )
-    )
+    );
+    assert_eq!(
+      "https://docs.qq.com/sheet/DV2ZxYWdNRUl6Q3Zx?tab=BB08J2",
+      replace_qqdocs(
+        "https://docs.qq.com/sheet/DV2ZxYWdNRUl6Q3Zx?tab=BB08J2&u=0123456789abcdef&scene=share"
+      )
+    );
+    assert_eq!(
+      "open https://docs.qq.com/doc/DV2ZxYWdNRUl6Q3Zx",
+      replace_qqdocs("open docs.qq.com/doc/DV2ZxYWdNRUl6Q3Zx?u=0123456789abcdef")
+    );
   }
 
   #[test]
-  fn replace_jd_test() {
+  fn replace_qq_middle_test() {
     assert_eq!(
-      "https://item.m.jd.com/product/100026923531.html",
-      replace_jd("https://item.m.jd.com/product/100026923531.html?&utm_source=iosapp&utm_medium=appshare&utm_campaign=114514&utm_term=CopyURL&ad_od=share&gx=T2nEPztRx6NTRa30RpDCM")
-    )
+      "https://example.com/a?b=1",
+      replace_qq_middle("https://c.pc.qq.com/middlem.html?pfurl=https%3A%2F%2Fexample.com%2Fa%3Fb%3D1&pfuin=12345&pfto=qq.msg&gjsublevel=2804&gjlevel=2")
+    );
+    assert_eq!(
+      "https://example.com/",
+      replace_qq_middle("c.pc.qq.com/middlem.html?pfurl=https%3A%2F%2Fexample.com%2F&pfuin=12345")
+    );
+    let no_target = "https://c.pc.qq.com/middlem.html?pfuin=12345&pfto=qq.msg";
+    assert_eq!(no_target, replace_qq_middle(no_target));
   }
 
   #[tokio::test]
-  async fn replace_twitter_short_test() {
-    let text = "https://t.co/jqpeEFD8Nz".to_string();
-    let result = replace_twitter_short(&text).await.unwrap();
-    assert_eq!("https://sdl.moe/", result)
+  async fn replace_urlcn_short_test() {
+    let resolver =
+      mock("https://docs.qq.com/doc/DV2ZxYWdNRUl6Q3Zx?u=0123456789abcdef&utm_source=qq");
+    assert_eq!(
+      "https://docs.qq.com/doc/DV2ZxYWdNRUl6Q3Zx",
+      replace_urlcn_short("https://url.cn/a1B2c3", &resolver)
+        .await
+        .unwrap()
+    );
   }
 
   #[tokio::test]
-  async fn replace_tiktok_share_test() {
-    let text_1 = "https://www.tiktok.com/t/ZSLLFK1V4/?t=1".to_string();
-    let result_1 = replace_tiktok_share(&text_1).await.unwrap();
+  async fn replace_nico_short_test() {
+    let resolver = mock("https://www.nicovideo.jp/watch/sm12345678?cp_in=wt_share&ref=nicoms");
+    assert_eq!(
+      "https://www.nicovideo.jp/watch/sm12345678",
+      replace_nico_short("https://nico.ms/sm12345678?cp_in=wt_share", &resolver)
+        .await
+        .unwrap()
+    );
+  }
+
+  #[test]
+  fn replace_gmaps_test() {
+    assert_eq!(
+      "https://www.google.com/maps/place/Tokyo+Tower/@35.6585805,139.7454329,17z/data=!3m1!4b1!4m6!3m5!1s0x60188bbd9009ec09:0x481a93f0d2a409dd",
+      replace_gmaps("https://www.google.com/maps/place/Tokyo+Tower/@35.6585805,139.7454329,17z/data=!3m1!4b1!4m6!3m5!1s0x60188bbd9009ec09:0x481a93f0d2a409dd?entry=ttu&g_ep=EgoyMDI0MTAwOS4wIKXMDSoASAFQAw%3D%3D")
+    );
+    assert_eq!(
+      "https://maps.google.com/maps?q=Tokyo+Tower",
+      replace_gmaps("maps.google.com/maps?q=Tokyo+Tower&hl=en&shorturl=1")
+    );
+    let clean = "https://www.google.com/maps/@35.6585805,139.7454329,15z";
+    assert_eq!(clean, replace_gmaps(clean));
+  }
+
+  #[tokio::test]
+  async fn replace_gmaps_short_test() {
+    let resolver = mock(
+      "https://www.google.com/maps/place/Tokyo+Tower/@35.6585805,139.7454329,17z?entry=tts&g_ep=EgoyMDI0&skid=1a2b3c",
+    );
     assert_eq!(
-      "https://www.tiktok.com/@omi_kim/video/7145033030191549697",
-      result_1
+      "https://www.google.com/maps/place/Tokyo+Tower/@35.6585805,139.7454329,17z",
+      replace_gmaps_short("https://maps.app.goo.gl/AbCdEf123456", &resolver)
+        .await
+        .unwrap()
     );
+  }
 
-    let text_2 = "https://vt.tiktok.com/ZSLd5tSKG/".to_string();
-    let result_2 = replace_tiktok_share(&text_2).await.unwrap();
+  #[tokio::test]
+  async fn replace_linee_short_test() {
+    let resolver =
+      mock("https://page.line.me/abc1234d?openQrModal=true&utm_source=share&utm_medium=line");
+    assert_eq!(
+      "see https://page.line.me/abc1234d?openQrModal=true",
+      replace_linee_short("see https://lin.ee/AbC1dEf", &resolver)
+        .await
+        .unwrap()
+    );
+  }
 
+  #[tokio::test]
+  async fn replace_kakao_short_test() {
+    let resolver =
+      mock("https://place.map.kakao.com/12345678?utm_source=kakaotalk&utm_campaign=share");
     assert_eq!(
-      "https://www.tiktok.com/@zaki_tuber/video/7234942299489291522",
-      result_2
+      "https://place.map.kakao.com/12345678",
+      replace_kakao_short("https://kko.to/a1B2c3-D4", &resolver)
+        .await
+        .unwrap()
     );
+  }
 
-    let text_3 = "https://vm.tiktok.com/ZSeNPcNM2/".to_string();
-    let result_3 = replace_tiktok_share(&text_3).await.unwrap();
+  #[tokio::test]
+  async fn replace_douyin_share_test() {
+    let text = "7.43 复制打开抖音，看看【某某的作品】# 日常 https://v.douyin.com/iRNBho6u/ a@B.gO 02/15 Sla:/";
+    let resolver = mock(
+      "https://www.iesdouyin.com/share/video/7339813454938574091/?region=CN&mid=7339813502162279195&u_code=0&did=MS4wLjABAAAA&with_sec_did=1",
+    );
+    assert_eq!(
+      "7.43 复制打开抖音，看看【某某的作品】# 日常 https://www.douyin.com/video/7339813454938574091 a@B.gO 02/15 Sla:/",
+      replace_douyin_share(text, &resolver).await.unwrap()
+    );
 
+    let resolver =
+      mock("https://www.douyin.com/video/7339813454938574091?previous_page=app_code_link");
     assert_eq!(
-      "https://www.tiktok.com/@kabyi_lame/video/7013423699755896070",
-      result_3
+      "https://www.douyin.com/video/7339813454938574091",
+      replace_douyin_share("https://v.douyin.com/iRNBho6u/", &resolver)
+        .await
+        .unwrap()
     );
   }
+
+  #[tokio::test]
+  async fn replace_tiktok_share_test() {
+    let cases = [
+      (
+        "https://www.tiktok.com/t/ZSLLFK1V4/?t=1",
+        "https://www.tiktok.com/@omi_kim/video/7145033030191549697?_r=1&_t=8WPqLqvXpGs&is_from_webapp=v1",
+        "https://www.tiktok.com/@omi_kim/video/7145033030191549697",
+      ),
+      (
+        "https://vt.tiktok.com/ZSLd5tSKG/",
+        "https://www.tiktok.com/@zaki_tuber/video/7234942299489291522?_r=1&u_code=dfm4l6k9f5kl0k&preview_pb=0&sharer_language=en",
+        "https://www.tiktok.com/@zaki_tuber/video/7234942299489291522",
+      ),
+      (
+        "https://vm.tiktok.com/ZSeNPcNM2/",
+        "https://www.tiktok.com/@kabyi_lame/video/7013423699755896070?is_from_webapp=1&sender_device=pc",
+        "https://www.tiktok.com/@kabyi_lame/video/7013423699755896070",
+      ),
+    ];
+    for (text, target, expected) in cases {
+      let result = replace_tiktok_share(text, &mock(target)).await.unwrap();
+      assert_eq!(expected, result);
+    }
+  }
 }
@@ -0,0 +1,140 @@
+use std::{fmt::Display, future::Future, time::Duration};
+
+use log::warn;
+
+use crate::Retry;
+
+/// How a failed operation should be retried.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RetryHint {
+  /// Not transient, give up immediately
+  Never,
+  /// Transient, retry with exponential backoff
+  Backoff,
+  /// Rate limited, retry after the given delay
+  After(Duration),
+}
+
+pub(crate) trait Transient {
+  fn retry_hint(&self) -> RetryHint;
+}
+
+impl Transient for frankenstein::Error {
+  fn retry_hint(&self) -> RetryHint {
+    match self {
+      frankenstein::Error::Api(resp) => match resp.error_code {
+        429 => resp
+          .parameters
+          .as_ref()
+          .and_then(|params| params.retry_after)
+          .map_or(RetryHint::Backoff, |secs| {
+            RetryHint::After(Duration::from_secs(secs.into()))
+          }),
+        500..=599 => RetryHint::Backoff,
+        _ => RetryHint::Never,
+      },
+      frankenstein::Error::HttpReqwest(_) => RetryHint::Backoff,
+      _ => RetryHint::Never,
+    }
+  }
+}
+
+/// Runs `op` up to `config.max_attempts` times while it fails with a transient error.
+pub(crate) async fn with_retry<T, E, F, Fut>(config: &Retry, mut op: F) -> Result<T, E>
+where
+  E: Transient + Display,
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T, E>>,
+{
+  let mut attempt = 1;
+  loop {
+    let err = match op().await {
+      Ok(value) => return Ok(value),
+      Err(err) => err,
+    };
+    if attempt >= config.max_attempts {
+      return Err(err);
+    }
+    let delay = match err.retry_hint() {
+      RetryHint::Never => return Err(err),
+      RetryHint::Backoff => Duration::from_millis(
+        config
+          .base_delay_ms
+          .saturating_mul(1 << (attempt - 1).min(16)),
+      ),
+      RetryHint::After(delay) => delay,
+    };
+    warn!(
+      "Attempt {attempt}/{} failed: {err}, retrying in {delay:?}",
+      config.max_attempts
+    );
+    tokio::time::sleep(delay).await;
+    attempt += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+
+  use super::*;
+
+  #[derive(Debug)]
+  struct MockError(RetryHint);
+
+  impl Transient for MockError {
+    fn retry_hint(&self) -> RetryHint {
+      self.0.clone()
+    }
+  }
+
+  impl Display for MockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      write!(f, "{:?}", self.0)
+    }
+  }
+
+  const CONFIG: Retry = Retry {
+    max_attempts: 3,
+    base_delay_ms: 1,
+  };
+
+  #[tokio::test]
+  async fn retries_transient_errors() {
+    let calls = Cell::new(0);
+    let result = with_retry(&CONFIG, || async {
+      calls.set(calls.get() + 1);
+      if calls.get() < 3 {
+        Err(MockError(RetryHint::Backoff))
+      } else {
+        Ok(calls.get())
+      }
+    })
+    .await;
+    assert_eq!(3, result.unwrap());
+  }
+
+  #[tokio::test]
+  async fn gives_up_after_max_attempts() {
+    let calls = Cell::new(0);
+    let result: Result<(), _> = with_retry(&CONFIG, || async {
+      calls.set(calls.get() + 1);
+      Err(MockError(RetryHint::After(Duration::from_millis(1))))
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(3, calls.get());
+  }
+
+  #[tokio::test]
+  async fn does_not_retry_permanent_errors() {
+    let calls = Cell::new(0);
+    let result: Result<(), _> = with_retry(&CONFIG, || async {
+      calls.set(calls.get() + 1);
+      Err(MockError(RetryHint::Never))
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(1, calls.get());
+  }
+}
@@ -0,0 +1,68 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::leaderboard::Poster;
+
+/// Runtime state persisted between restarts.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct State {
+  /// Next `update_id` to request from `getUpdates`.
+  pub(crate) offset: Option<i64>,
+  /// Saved with `persist-leaderboard` only.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub(crate) leaderboard: Vec<Poster>,
+}
+
+impl State {
+  /// Loads state from `path`, a missing file yields the default state.
+  pub(crate) fn load(path: &Path) -> Result<State> {
+    let str = match fs::read_to_string(path) {
+      Ok(str) => str,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(State::default()),
+      Err(err) => {
+        return Err(err)
+          .with_context(|| format!("Failed to read state file: {}", path.to_string_lossy()))
+      },
+    };
+    toml::from_str(&str)
+      .with_context(|| format!("Failed to parse state file: {}", path.to_string_lossy()))
+  }
+
+  /// Writes state to a temporary file first, then renames it over `path`.
+  pub(crate) fn save(&self, path: &Path) -> Result<()> {
+    let str = toml::to_string(self).context("Failed to serialize state")?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, str)
+      .with_context(|| format!("Failed to write state file: {}", tmp.to_string_lossy()))?;
+    fs::rename(&tmp, path)
+      .with_context(|| format!("Failed to replace state file: {}", path.to_string_lossy()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip_offset() {
+    let path = std::env::temp_dir().join(format!("fuckburl-bot-state-{}.toml", std::process::id()));
+    assert_eq!(State::default(), State::load(&path).unwrap());
+
+    let state = State {
+      offset: Some(114514),
+      leaderboard: vec![Poster {
+        id: 1919810,
+        name: "Bob".to_string(),
+        username: None,
+        cleaned: 3,
+      }],
+    };
+    state.save(&path).unwrap();
+    assert_eq!(state, State::load(&path).unwrap());
+
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,119 @@
+use std::{
+  collections::HashMap,
+  fmt::Write,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+  },
+  time::Duration,
+};
+
+use fuckburl_bot::replacer::{Platform, ReplaceOutcome};
+
+/// Counters of what the bot has cleaned since it started.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+  messages: AtomicU64,
+  cleaned: AtomicU64,
+  replacements: AtomicU64,
+  platforms: Mutex<HashMap<Platform, u64>>,
+}
+
+impl Stats {
+  /// Counts a message that went through [`replace_all`](fuckburl_bot::replacer::replace_all).
+  pub(crate) fn record(&self, outcome: &ReplaceOutcome) {
+    self.messages.fetch_add(1, Ordering::Relaxed);
+    if !outcome.changed {
+      return;
+    }
+    self.cleaned.fetch_add(1, Ordering::Relaxed);
+    self
+      .replacements
+      .fetch_add(outcome.replacements.len() as u64, Ordering::Relaxed);
+    let mut platforms = self.platforms.lock().unwrap();
+    for replacement in &outcome.replacements {
+      *platforms.entry(replacement.platform).or_default() += 1;
+    }
+  }
+
+  /// Renders the counters for the `/stats` command.
+  pub(crate) fn summary(&self, uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let mut text = String::with_capacity(256);
+    writeln!(
+      text,
+      "Uptime: {}d {}h {}m {}s",
+      secs / 86400,
+      secs % 86400 / 3600,
+      secs % 3600 / 60,
+      secs % 60
+    )
+    .unwrap();
+    writeln!(
+      text,
+      "Messages processed: {}",
+      self.messages.load(Ordering::Relaxed)
+    )
+    .unwrap();
+    writeln!(
+      text,
+      "Messages cleaned: {}",
+      self.cleaned.load(Ordering::Relaxed)
+    )
+    .unwrap();
+    write!(
+      text,
+      "Links cleaned: {}",
+      self.replacements.load(Ordering::Relaxed)
+    )
+    .unwrap();
+
+    let mut platforms = self
+      .platforms
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(platform, count)| (platform.to_string(), *count))
+      .collect::<Vec<_>>();
+    platforms.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    for (platform, count) in platforms {
+      write!(text, "\n  {platform}: {count}").unwrap();
+    }
+    text
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use fuckburl_bot::replacer::Replacement;
+
+  use super::*;
+
+  fn outcome(platforms: &[Platform]) -> ReplaceOutcome {
+    ReplaceOutcome {
+      text: String::new(),
+      changed: !platforms.is_empty(),
+      replacements: platforms
+        .iter()
+        .map(|&platform| Replacement {
+          platform,
+          original: String::new(),
+          replaced: String::new(),
+        })
+        .collect(),
+      affiliate_params: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn counts_per_platform() {
+    let stats = Stats::default();
+    stats.record(&outcome(&[Platform::Twitter, Platform::Bilibili]));
+    stats.record(&outcome(&[Platform::Bilibili]));
+    stats.record(&outcome(&[]));
+    assert_eq!(
+      "Uptime: 1d 1h 1m 1s\nMessages processed: 3\nMessages cleaned: 2\nLinks cleaned: 3\n  bilibili: 2\n  twitter: 1",
+      stats.summary(Duration::from_secs(90061))
+    );
+  }
+}
@@ -0,0 +1,82 @@
+/// Telegram API error description, if `err` is an error reported by the API.
+pub(crate) fn api_description(err: &frankenstein::Error) -> Option<&str> {
+  match err {
+    frankenstein::Error::Api(resp) => Some(&resp.description),
+    _ => None,
+  }
+}
+
+/// The bot lacks the rights to delete messages in the chat.
+pub(crate) fn is_missing_delete_permission(description: &str) -> bool {
+  description.contains("not enough rights") || description.ends_with("message can't be deleted")
+}
+
+/// The message is older than the 48 hours within which bots can delete messages.
+pub(crate) fn is_too_old_to_delete(description: &str) -> bool {
+  description.contains("message can't be deleted for everyone")
+}
+
+/// The user never started a conversation with the bot, or blocked it.
+pub(crate) fn is_dm_unavailable(description: &str) -> bool {
+  description.contains("bot can't initiate conversation")
+    || description.contains("bot was blocked by the user")
+}
+
+/// The chat is gone or the bot was removed from it, so nothing can be posted there anymore.
+pub(crate) fn is_chat_gone(description: &str) -> bool {
+  description.contains("chat not found")
+    || description.contains("bot was kicked")
+    || description.contains("bot is not a member")
+    || description.contains("group chat was deleted")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn missing_delete_permission() {
+    assert!(is_missing_delete_permission(
+      "Bad Request: message can't be deleted"
+    ));
+    assert!(is_missing_delete_permission(
+      "Bad Request: not enough rights to delete messages"
+    ));
+    assert!(!is_missing_delete_permission(
+      "Bad Request: message to delete not found"
+    ));
+  }
+
+  #[test]
+  fn too_old_to_delete() {
+    let description = "Bad Request: message can't be deleted for everyone";
+    assert!(is_too_old_to_delete(description));
+    assert!(!is_missing_delete_permission(description));
+    assert!(!is_too_old_to_delete(
+      "Bad Request: message can't be deleted"
+    ));
+  }
+
+  #[test]
+  fn dm_unavailable() {
+    assert!(is_dm_unavailable(
+      "Forbidden: bot can't initiate conversation with a user"
+    ));
+    assert!(is_dm_unavailable("Forbidden: bot was blocked by the user"));
+    assert!(!is_dm_unavailable("Bad Request: chat not found"));
+  }
+
+  #[test]
+  fn chat_gone() {
+    assert!(is_chat_gone("Bad Request: chat not found"));
+    assert!(is_chat_gone(
+      "Forbidden: bot was kicked from the supergroup chat"
+    ));
+    assert!(is_chat_gone(
+      "Forbidden: bot is not a member of the channel chat"
+    ));
+    assert!(is_chat_gone("Forbidden: the group chat was deleted"));
+    assert!(!is_chat_gone("Bad Request: message to reply not found"));
+    assert!(!is_chat_gone("Forbidden: bot was blocked by the user"));
+  }
+}
@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use axum::{
+  body::Bytes,
+  extract::State,
+  http::{HeaderMap, StatusCode},
+  routing::post,
+  Router,
+};
+use frankenstein::{AsyncApi, AsyncTelegramApi, SetWebhookParams, Update};
+use log::{debug, info, warn};
+use tokio::{net::TcpListener, task::JoinSet};
+use url::Url;
+
+use crate::{
+  allowed_updates, drain_tasks, event::spawn_process_update, shutdown_signal, Config, Shared,
+};
+
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+#[derive(Clone)]
+struct WebhookState {
+  api: Arc<AsyncApi>,
+  /// The startup config, the webhook itself isn't reloaded
+  config: Arc<Config>,
+  shared: Arc<Shared>,
+  tasks: Arc<Mutex<JoinSet<()>>>,
+}
+
+pub(crate) async fn serve(
+  api: Arc<AsyncApi>,
+  config: Arc<Config>,
+  shared: Arc<Shared>,
+) -> Result<()> {
+  let webhook = config
+    .webhook
+    .as_ref()
+    .context("Webhook is not configured")?;
+
+  let path = Url::parse(&webhook.url)
+    .with_context(|| format!("Failed to parse webhook url: {}", webhook.url))?
+    .path()
+    .to_string();
+
+  let listener = TcpListener::bind(webhook.listen_addr)
+    .await
+    .with_context(|| format!("Failed to listen on {}", webhook.listen_addr))?;
+
+  let params = SetWebhookParams::builder()
+    .url(webhook.url.clone())
+    .secret_token(webhook.secret_token.clone())
+    .allowed_updates(allowed_updates(&config))
+    .build();
+  api
+    .set_webhook(&params)
+    .await
+    .context("Failed to set telegram webhook")?;
+  info!(
+    "Webhook set to {}, listening on {}",
+    webhook.url, webhook.listen_addr
+  );
+
+  let tasks = Arc::new(Mutex::new(JoinSet::new()));
+  let state = WebhookState {
+    api,
+    config: Arc::clone(&config),
+    shared,
+    tasks: Arc::clone(&tasks),
+  };
+  let app = Router::new()
+    .route(&path, post(handle_update))
+    .with_state(state);
+
+  axum::serve(listener, app)
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .context("Webhook server exited unexpectedly")?;
+
+  info!("Shutting down...");
+  let tasks = std::mem::take(&mut *tasks.lock().unwrap());
+  drain_tasks(tasks).await;
+  Ok(())
+}
+
+async fn handle_update(
+  State(state): State<WebhookState>,
+  headers: HeaderMap,
+  body: Bytes,
+) -> StatusCode {
+  let Some(webhook) = &state.config.webhook else {
+    return StatusCode::NOT_FOUND;
+  };
+
+  let token = headers
+    .get(SECRET_TOKEN_HEADER)
+    .and_then(|value| value.to_str().ok());
+  if token != Some(webhook.secret_token.as_str()) {
+    warn!("Rejected webhook request with mismatched secret token");
+    return StatusCode::UNAUTHORIZED;
+  }
+
+  let update: Update = match serde_json::from_slice(&body) {
+    Ok(update) => update,
+    Err(err) => {
+      warn!("Failed to parse webhook update: {err}");
+      return StatusCode::BAD_REQUEST;
+    },
+  };
+  debug!("Webhook update: {}", update.update_id);
+
+  let mut tasks = state.tasks.lock().unwrap();
+  while tasks.try_join_next().is_some() {}
+  let config = state.shared.config();
+  spawn_process_update(&mut tasks, state.api, config, state.shared, update);
+  StatusCode::OK
+}